@@ -95,6 +95,7 @@ interface Item {
   supplier_notes?: string
   pieces_per_bulk?: number
   unit?: string
+  version: number
 }
 
 // Sort type
@@ -188,6 +189,7 @@ const convertSupplyToItem = (supply: any, index: number): Item => {
     supplier_notes: supply.supplier_notes,
     pieces_per_bulk,
     unit: supply.unit,
+    version: supply.version,
   }
 }
 
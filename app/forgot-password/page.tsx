@@ -55,7 +55,7 @@ export default function ForgotPasswordPage() {
         setShowResetForm(true)
         toast({
           title: "Success!",
-          description: "Password reset email sent! You can now reset your password below.",
+          description: "If an account uses that email, a reset code is on its way. You can reset your password below.",
         })
       } else {
         throw new Error(response?.error || "Failed to send reset link")
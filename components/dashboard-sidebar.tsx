@@ -24,6 +24,7 @@ import { Button } from "@/components/ui/button"
 import { Badge } from "@/components/ui/badge"
 import { Separator } from "@/components/ui/separator"
 import { getCurrentUser, hasPermission } from "@/lib/permissions"
+import tauriApiService from "@/components/services/tauriApiService"
 import {
   AlertDialog,
   AlertDialogAction,
@@ -102,18 +103,19 @@ export default function DashboardSidebar() {
   }
 
   // Handle logout
-  const handleLogout = () => {
+  const handleLogout = async () => {
     try {
-      // Clear authentication data
-      localStorage.removeItem("isLoggedIn")
-      localStorage.removeItem("user")
-      localStorage.removeItem("token")
-
-      // Redirect to login page
-      router.push("/")
+      // End the backend session while the token is still stored
+      await tauriApiService.logout()
     } catch (error) {
       // Silently handle logout errors
     }
+
+    // Clear authentication data and redirect to login page
+    localStorage.removeItem("isLoggedIn")
+    localStorage.removeItem("user")
+    localStorage.removeItem("token")
+    router.push("/")
   }
 
   const getRoleColor = (role: string) => {
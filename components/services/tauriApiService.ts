@@ -13,7 +13,6 @@ const isTauriAvailable = typeof window !== 'undefined' && (
 interface User {
   id: string;
   username: string;
-  password: string;
   firstname: string;
   lastname: string;
   email: string;
@@ -42,6 +41,7 @@ interface Supply {
   pieces_per_bulk?: number;
   created_at: string;
   updated_at: string;
+  version: number;
 }
 
 interface SupplyHistory {
@@ -58,16 +58,34 @@ interface SupplyHistory {
 
 interface EnrichedSupplyHistory {
   id: string;
-  supply_id: string;
+  supply_id: string | null;
   supply_name: string;
   action: string;
   quantity: number;
   previous_quantity: number;
   new_quantity: number;
   notes?: string;
-  user_id: string;
+  user_id: string | null;
   user_name: string;
   created_at: string;
+  // Only set on movements recorded through stockIn, stockOut and adjustStock
+  reason_code?: string | null;
+  reference_number?: string | null;
+  department?: string | null;
+}
+
+// Every failed command rejects with this shape
+export type ApiErrorCode = 'NOT_FOUND' | 'CONFLICT' | 'VALIDATION' | 'FORBIDDEN' | 'INTERNAL';
+
+export interface ApiError {
+  code: ApiErrorCode;
+  message: string;
+  // e.g. `current` holds the latest row when an edit conflicts with someone else's
+  details?: Record<string, any>;
+}
+
+export function isApiError(error: unknown): error is ApiError {
+  return typeof error === 'object' && error !== null && 'code' in error && 'message' in error;
 }
 
 interface LoginRequest {
@@ -78,6 +96,8 @@ interface LoginRequest {
 interface LoginResponse {
   success: boolean;
   user?: User;
+  token?: string;
+  expires_at?: string;
   error?: string;
 }
 
@@ -138,6 +158,23 @@ interface UpdateSupplyRequest {
   pieces_per_bulk?: number;
   stock_in_reason?: string;
   stock_out_reason?: string;
+  // Version of the supply the edit is based on; a stale version is rejected with CONFLICT
+  version: number;
+}
+
+// Reason codes accepted by stock_in, stock_out and adjust_stock respectively
+export const STOCK_IN_REASONS = ['purchase', 'return', 'transfer_in', 'donation', 'other'] as const;
+export const STOCK_OUT_REASONS = ['issued', 'transfer_out', 'damaged', 'expired', 'other'] as const;
+export const ADJUSTMENT_REASONS = ['count_correction', 'damaged', 'lost', 'found', 'other'] as const;
+
+interface StockMovementRequest {
+  supply_id: string;
+  // Pieces received or released; for an adjustment, the signed correction
+  quantity: number;
+  reason_code: string;
+  reference_number: string;
+  department?: string;
+  notes?: string;
 }
 
 class TauriApiService {
@@ -207,6 +244,32 @@ class TauriApiService {
     }
   }
 
+  // Session token issued by the backend at login
+  private getSessionToken(): string {
+    const token = localStorage.getItem('token');
+    if (!token) {
+      throw new Error('User not found in session');
+    }
+    return token;
+  }
+
+  // First-run setup
+  async getSetupStatus(): Promise<{ needs_setup: boolean }> {
+    await this.waitForInitialization();
+    if (!this.invoke) {
+      throw new Error('Tauri invoke function not available');
+    }
+    return await this.invoke('setup_status') as { needs_setup: boolean };
+  }
+
+  async completeSetup(admin: Omit<CreateUserRequest, 'role' | 'permissions'>): Promise<string> {
+    await this.waitForInitialization();
+    if (!this.invoke) {
+      throw new Error('Tauri invoke function not available');
+    }
+    return await this.invoke('complete_setup', { request: admin }) as string;
+  }
+
   // Authentication
   async login(username: string, password: string): Promise<LoginResponse> {
     // Wait for initialization to complete
@@ -223,15 +286,33 @@ class TauriApiService {
       const response = await this.invoke('login', {
         request: { username, password }
       }) as LoginResponse;
+      if (response.success && response.token) {
+        localStorage.setItem('token', response.token);
+      }
       return response;
     } catch (error) {
       return {
         success: false,
-        error: error instanceof Error ? error.message : 'Login failed'
+        error: error instanceof Error || isApiError(error) ? error.message : 'Login failed'
       };
     }
   }
 
+  async logout(): Promise<void> {
+    // Take the token before awaiting, so a caller clearing storage can't race us
+    const token = localStorage.getItem('token');
+    localStorage.removeItem('token');
+    await this.waitForInitialization();
+    if (!this.invoke || !token) {
+      return;
+    }
+    try {
+      await this.invoke('logout', { session_token: token });
+    } catch (error) {
+      // The session is already gone locally
+    }
+  }
+
   // User management
   async getUsers(): Promise<User[]> {
     try {
@@ -239,7 +320,7 @@ class TauriApiService {
       if (!this.invoke) {
         throw new Error('Tauri invoke function not available');
       }
-      const response = await this.invoke('get_users') as any;
+      const response = await this.invoke('get_users', { session_token: this.getSessionToken() }) as any;
       
       // Handle different response formats
       if (Array.isArray(response)) {
@@ -263,16 +344,11 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       return await this.invoke('create_user', {
         request: userData,
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
     } catch (error) {
       throw error;
@@ -286,19 +362,14 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       return await this.invoke('update_user', {
         request: {
           id: userId,
           ...userData
         },
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
     } catch (error) {
       throw error;
@@ -312,16 +383,11 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       return await this.invoke('delete_user', {
         user_id: userId,
-        current_user_id: user.id
+        session_token: sessionToken
       }) as string;
     } catch (error) {
       throw error;
@@ -337,7 +403,7 @@ class TauriApiService {
       if (!this.invoke) {
         throw new Error('Tauri invoke function not available');
       }
-      return await this.invoke('get_supplies') as Supply[];
+      return await this.invoke('get_supplies', { session_token: this.getSessionToken() }) as Supply[];
     } catch (error) {
       throw error;
     }
@@ -350,16 +416,11 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       const result = await this.invoke('create_supply', {
         request: supplyData,
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
       
       // Clear related caches
@@ -379,19 +440,14 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       const result = await this.invoke('update_supply', {
         request: {
           id: supplyId,
           ...supplyData
         },
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
       
       // Clear related caches
@@ -404,6 +460,32 @@ class TauriApiService {
     }
   }
 
+  // A stock-out that would take the quantity below zero rejects with CONFLICT
+  // and details.available
+  async stockIn(request: StockMovementRequest): Promise<Supply> {
+    return this.recordStockMovement('stock_in', request);
+  }
+
+  async stockOut(request: StockMovementRequest): Promise<Supply> {
+    return this.recordStockMovement('stock_out', request);
+  }
+
+  async adjustStock(request: StockMovementRequest): Promise<Supply> {
+    return this.recordStockMovement('adjust_stock', request);
+  }
+
+  private async recordStockMovement(command: string, request: StockMovementRequest): Promise<Supply> {
+    await this.waitForInitialization();
+    if (!this.invoke) {
+      throw new Error('Tauri invoke function not available');
+    }
+
+    return await this.invoke(command, {
+      request,
+      session_token: this.getSessionToken()
+    }) as Supply;
+  }
+
   async deleteSupply(supplyId: string): Promise<string> {
     try {
       await this.waitForInitialization();
@@ -411,16 +493,11 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       return await this.invoke('delete_supply', { 
         supply_id: supplyId,
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
     } catch (error) {
       throw error;
@@ -434,16 +511,11 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       
-      // Get current user from localStorage
-      const userStr = localStorage.getItem('user');
-      if (!userStr) {
-        throw new Error('User not found in session');
-      }
-      const user = JSON.parse(userStr);
+      const sessionToken = this.getSessionToken();
       
       return await this.invoke('delete_supply_history', { 
         history_id: historyId,
-        user_id: user.id
+        session_token: sessionToken
       }) as string;
     } catch (error) {
       throw error;
@@ -457,7 +529,7 @@ class TauriApiService {
       if (!this.invoke) {
         throw new Error('Tauri invoke function not available');
       }
-      return await this.invoke('get_supply_histories') as EnrichedSupplyHistory[];
+      return await this.invoke('get_supply_histories', { session_token: this.getSessionToken() }) as EnrichedSupplyHistory[];
     } catch (error) {
       throw error;
     }
@@ -554,7 +626,7 @@ class TauriApiService {
       if (!this.invoke) {
         throw new Error('Tauri invoke function not available');
       }
-      return await this.invoke('recalculate_stock_status') as string;
+      return await this.invoke('recalculate_stock_status', { session_token: this.getSessionToken() }) as string;
     } catch (error) {
       throw new Error('Failed to recalculate stock status');
     }
@@ -580,12 +652,12 @@ class TauriApiService {
         throw new Error('Tauri invoke function not available');
       }
       // Try to get users as a simple database connectivity test
-      await this.invoke('get_users');
+      await this.invoke('get_users', { session_token: this.getSessionToken() });
       return { status: 'connected' };
     } catch (error) {
       return { 
         status: 'error', 
-        message: error instanceof Error ? error.message : 'Unknown database error' 
+        message: error instanceof Error || isApiError(error) ? error.message : 'Unknown database error' 
       };
     }
   }
@@ -16,7 +16,7 @@ import { Tooltip, TooltipContent, TooltipProvider, TooltipTrigger } from "@/comp
 import { Alert, AlertDescription } from "@/components/ui/alert"
 import { Badge } from "@/components/ui/badge"
 import { Card, CardContent, CardHeader, CardTitle } from "@/components/ui/card"
-import tauriApiService from "./services/tauriApiService"
+import tauriApiService, { isApiError } from "./services/tauriApiService"
 import { getVariationOptions } from "@/lib/variation-utils"
 import { calculateStockStatus } from "@/lib/utils"
 
@@ -97,6 +97,7 @@ interface Item {
   supplier_notes?: string
   pieces_per_bulk?: number
   unit?: string
+  version: number
 }
 
 interface UpdateItemDialogProps {
@@ -130,6 +131,8 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
   const [isSubmitting, setIsSubmitting] = useState(false)
   const [error, setError] = useState<string | null>(null)
   const [validationErrors, setValidationErrors] = useState<{ [key: string]: string }>({})
+  // Set when someone else saved the item while this dialog was open
+  const [latest, setLatest] = useState<{ quantity: number; version: number } | null>(null)
   const [formData, setFormData] = useState({
     addBulk: "",
     addPieces: "",
@@ -166,6 +169,7 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
       supplierNotes: item.supplier_notes || "",
       piecesPerBulk: item.pieces_per_bulk?.toString() || defaultPiecesPerBulk.toString(),
     }))
+    setLatest(null)
   }, [item])
 
   // Helper function to get the item name regardless of the column name used
@@ -253,10 +257,11 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
     
     setIsSubmitting(true)
     setValidationErrors({})
+    setError(null)
     
     try {
       // Calculate new quantity
-      const currentQuantity = item.pieces || item.pieces_quantity || 0
+      const currentQuantity = latest ? latest.quantity : item.pieces || item.pieces_quantity || 0
       const piecesPerBulk = Number.parseInt(formData.piecesPerBulk) || 12
       const addBulkPieces = addBulk * piecesPerBulk
       const releaseBulkPieces = releaseBulk * piecesPerBulk
@@ -286,7 +291,8 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
         supplier_notes: formData.supplierNotes || item.supplier_notes || null,
         pieces_per_bulk: Number.parseInt(formData.piecesPerBulk) || item.pieces_per_bulk || 12,
         stock_in_reason: formData.stockInReason || null,
-        stock_out_reason: formData.stockOutReason || null
+        stock_out_reason: formData.stockOutReason || null,
+        version: latest ? latest.version : item.version,
       }
       
       await tauriApiService.updateSupply(supplyId, supplyData)
@@ -301,6 +307,13 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
         onSuccess()
       }
     } catch (error: any) {
+      if (isApiError(error) && error.code === "CONFLICT" && error.details?.current) {
+        // Keep the form; the stock adjustments are applied to the latest quantity on the next submit
+        const current = error.details.current
+        setLatest({ quantity: current.quantity, version: current.version })
+        setError(`Someone else updated this item while you were editing. The stock is now ${current.quantity} pieces; review your changes and submit again to apply them to it.`)
+        return
+      }
       toast({
         title: "Error",
         description: error.message || "Failed to update item",
@@ -312,7 +325,7 @@ export function UpdateItemDialog({ open, onOpenChange, item, onSuccess }: Update
   }
 
   // Calculate preview values
-  const currentQuantity = item.pieces || item.pieces_quantity || 0
+  const currentQuantity = latest ? latest.quantity : item.pieces || item.pieces_quantity || 0
   const piecesPerBulk = Number.parseInt(formData.piecesPerBulk) || 12
   const addBulk = Number.parseInt(formData.addBulk) || 0
   const addPieces = Number.parseInt(formData.addPieces) || 0
@@ -0,0 +1,10 @@
+use std::str::FromStr;
+
+// Read a setting from the environment, falling back to the built-in default
+// when the variable is missing or does not parse
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
@@ -1,10 +1,13 @@
-use rusqlite::{Connection, Result, params, OptionalExtension};
+use rusqlite::{Connection, params, OptionalExtension};
 use serde::{Deserialize, Serialize};
-use bcrypt::{hash, verify, DEFAULT_COST};
 use uuid::Uuid;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
 
+use crate::error::{AppError, AppResult};
+use crate::migrations;
+use crate::reset_tokens::hash_token;
+
 #[derive(Serialize, Deserialize)]
 pub struct UpdateSupplyRequest {
     pub id: String,
@@ -27,6 +30,9 @@ pub struct UpdateSupplyRequest {
     pub pieces_per_bulk: Option<i32>,
     pub stock_in_reason: Option<String>,
     pub stock_out_reason: Option<String>,
+    // The version of the supply the edit was made against; the update is refused
+    // if the supply has changed since
+    pub version: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -40,7 +46,8 @@ pub struct UpdateUserRequest {
     pub permissions: String,
 }
 
-#[derive(Serialize, Deserialize)]
+// Internal user row; holds the password hash and is deliberately not serializable.
+// Use `PublicUser` for anything sent to the frontend.
 pub struct User {
     pub id: String,
     pub username: String,
@@ -54,6 +61,35 @@ pub struct User {
     pub updated_at: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct PublicUser {
+    pub id: String,
+    pub username: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub email: String,
+    pub role: String,
+    pub permissions: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        PublicUser {
+            id: user.id,
+            username: user.username,
+            firstname: user.firstname,
+            lastname: user.lastname,
+            email: user.email,
+            role: user.role,
+            permissions: user.permissions,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Supply {
     pub id: String,
@@ -76,6 +112,8 @@ pub struct Supply {
     pub pieces_per_bulk: Option<i32>,
     pub created_at: String,
     pub updated_at: String,
+    // Bumped on every edit; see UpdateSupplyRequest::version
+    pub version: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -94,16 +132,60 @@ pub struct SupplyHistory {
 #[derive(Serialize, Deserialize)]
 pub struct EnrichedSupplyHistory {
     pub id: String,
-    pub supply_id: String,
+    // None once the supply or user has been deleted
+    pub supply_id: Option<String>,
     pub supply_name: String,
     pub action: String,
     pub quantity: i32,
     pub previous_quantity: i32,
     pub new_quantity: i32,
     pub notes: Option<String>,
-    pub user_id: String,
+    pub user_id: Option<String>,
     pub user_name: String,
     pub created_at: String,
+    // Only set on movements recorded through stock_in, stock_out and adjust_stock
+    pub reason_code: Option<String>,
+    pub reference_number: Option<String>,
+    pub department: Option<String>,
+}
+
+// A stock-in, stock-out or adjustment. `quantity` is in pieces: the amount received or
+// released, or for an adjustment the signed correction.
+#[derive(Serialize, Deserialize)]
+pub struct StockMovementRequest {
+    pub supply_id: String,
+    pub quantity: i32,
+    pub reason_code: String,
+    pub reference_number: String,
+    pub department: Option<String>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StockMovement {
+    In,
+    Out,
+    Adjust,
+}
+
+impl StockMovement {
+    // The history action. In and Out match what update_supply records when an edit changes
+    // the quantity; adjustments have an action of their own.
+    fn action(&self) -> &'static str {
+        match self {
+            StockMovement::In => "Stock In",
+            StockMovement::Out => "Stock Out",
+            StockMovement::Adjust => "Stock Adjusted",
+        }
+    }
+
+    pub fn reason_codes(&self) -> &'static [&'static str] {
+        match self {
+            StockMovement::In => &["purchase", "return", "transfer_in", "donation", "other"],
+            StockMovement::Out => &["issued", "transfer_out", "damaged", "expired", "other"],
+            StockMovement::Adjust => &["count_correction", "damaged", "lost", "found", "other"],
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -116,12 +198,190 @@ pub struct PasswordResetToken {
     pub created_at: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SecurityEvent {
+    pub id: String,
+    pub event_type: String,
+    pub username: String,
+    pub user_id: Option<String>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SecurityEventFilter {
+    pub event_type: Option<String>,
+    pub username: Option<String>,
+    pub outcome: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub page: Option<u32>,
+    pub page_size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SecurityEventPage {
+    pub events: Vec<SecurityEvent>,
+    pub total: i64,
+    pub page: u32,
+    pub page_size: u32,
+}
+
+// Audit log target types
+pub const AUDIT_TARGET_USER: &str = "user";
+pub const AUDIT_TARGET_SUPPLY_HISTORY: &str = "supply_history";
+
+// An administrative action, e.g. a user being created or a history record deleted
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub actor_id: Option<String>,
+    // Username of the actor at the time, kept if the account is later deleted
+    pub actor_username: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct AuditLogFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub page: Option<u32>,
+    pub page_size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    pub total: i64,
+    pub page: u32,
+    pub page_size: u32,
+}
+
+// Rows pointing at a parent row that no longer exists
+#[derive(Serialize)]
+pub struct OrphanedRows {
+    pub table: String,
+    pub parent_table: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    // Problems reported by SQLite's own consistency check
+    pub problems: Vec<String>,
+    pub orphans: Vec<OrphanedRows>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RolePolicy {
+    pub role: String,
+    pub require_2fa: bool,
+    pub updated_at: String,
+}
+
+// Email outbox entry as shown to admins. The body is left out, it may hold a reset token.
+#[derive(Serialize, Deserialize)]
+pub struct OutboxEmail {
+    pub id: String,
+    pub to_address: String,
+    pub subject: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<String>,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+}
+
+// Email waiting for delivery by the outbox worker
+pub struct QueuedEmail {
+    pub id: String,
+    pub to_address: String,
+    pub subject: String,
+    pub body: String,
+    pub html_body: Option<String>,
+    pub attempts: i32,
+}
+
+// Subscription to the low-stock email digest
+#[derive(Serialize, Deserialize)]
+pub struct StockDigestSubscription {
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    // Also report items at "Moderate", not just "Low"
+    pub include_moderate: bool,
+    pub created_at: String,
+}
+
+// Stock statuses from calculate_stock_status
+pub const STOCK_STATUS_LOW: &str = "Low";
+pub const STOCK_STATUS_MODERATE: &str = "Moderate";
+pub const STOCK_STATUS_HIGH: &str = "High";
+
+// Largest quantity a single stock movement may move, well within range for the
+// quantity column however many movements follow
+pub const MAX_STOCK_MOVEMENT: i32 = 1_000_000;
+
+// Outbox statuses: waiting for (another) attempt, delivered, out of attempts
+pub const EMAIL_STATUS_PENDING: &str = "pending";
+pub const EMAIL_STATUS_SENT: &str = "sent";
+pub const EMAIL_STATUS_FAILED: &str = "failed";
+
+// Outgoing mail server settings. Like User, this is never serialized because of the password;
+// see smtp::masked for what admins are shown.
+#[derive(Clone)]
+pub struct SmtpSettings {
+    // smtp, file or memory, see mailer::MailTransport
+    pub transport: String,
+    pub host: String,
+    pub port: u16,
+    pub tls_mode: String,
+    pub username: String,
+    pub password: String,
+    pub from_address: String,
+    // Folder for the file transport; empty means the default next to the database
+    pub drop_dir: String,
+    // Branding used by the email templates
+    pub organization_name: String,
+    pub logo_url: String,
+}
+
+pub struct TotpState {
+    pub secret: Option<String>,
+    pub enabled: bool,
+    pub last_step: Option<i64>,
+}
+
+// Session scopes: only `full` sessions may call regular commands
+pub const SESSION_SCOPE_FULL: &str = "full";
+// Password accepted, waiting for the second factor
+pub const SESSION_SCOPE_2FA_PENDING: &str = "2fa_pending";
+// Role requires 2FA but the user has not enrolled yet; may only enroll
+pub const SESSION_SCOPE_2FA_SETUP: &str = "2fa_setup";
+// Password was set by an admin; may only change the password
+pub const SESSION_SCOPE_PASSWORD_CHANGE: &str = "password_change";
+
+// Shown in the history for supplies and users that no longer exist and have no snapshotted name
+pub const DELETED_SUPPLY_NAME: &str = "Deleted item";
+pub const DELETED_USER_NAME: &str = "Deleted user";
+
 pub struct Database {
     conn: Connection,
 }
 
 impl Database {
-    pub fn new() -> Result<Self> {
+    pub fn new() -> AppResult<Self> {
         let db_path = get_database_path();
         ensure_database_directory(&db_path)?;
         
@@ -129,128 +389,70 @@ impl Database {
         let is_fresh_install = !db_path.exists();
         
         // Simple connection with basic optimizations
-        let conn = Connection::open(&db_path)?;
+        let db = Database::open(Connection::open(&db_path)?)?;
+        
+        // Fresh installs start empty and go through the first-run setup.
+        // Demo builds (`demo-data` feature) still get the sample accounts and supplies.
+        if cfg!(feature = "demo-data") && is_fresh_install {
+            db.seed_sample_data_automatically()?;
+        }
         
+        Ok(db)
+    }
+
+    // Another connection to the same database that refuses to write, for the reader pool.
+    // Must be opened after `new`, which creates and migrates the database.
+    pub fn new_reader() -> AppResult<Self> {
+        Database::reader_at(&get_database_path())
+    }
+
+    pub fn reader_at(path: &Path) -> AppResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("
+            PRAGMA busy_timeout = 5000;
+            PRAGMA cache_size = -1000;
+            PRAGMA foreign_keys = ON;
+            PRAGMA query_only = ON;
+        ")?;
+        Ok(Database { conn })
+    }
+
+    // Writer connection to a database file somewhere else than the app's
+    #[cfg(test)]
+    pub fn open_path(path: &Path) -> AppResult<Self> {
+        Database::open(Connection::open(path)?)
+    }
+
+    fn open(mut conn: Connection) -> AppResult<Self> {
         // Basic SQLite optimizations for small databases
         conn.execute_batch("
             PRAGMA journal_mode = WAL;
             PRAGMA synchronous = NORMAL;
+            PRAGMA busy_timeout = 5000; -- wait rather than fail while another connection holds a lock
             PRAGMA cache_size = -1000; -- 1MB cache (sufficient for small office)
             PRAGMA foreign_keys = ON;
         ")?;
         
-        // Database connection opened successfully
-        
-        let db = Database { 
-            conn, 
-        };
-        
-        // Always initialize tables (safe with IF NOT EXISTS)
-        db.init_tables()?;
-        
-        // Admin user creation removed - keeping only the 4 custom accounts
-        // db.insert_default_admin()?;
-        
-        // Only seed sample data on fresh install
-        if is_fresh_install {
-            db.seed_sample_data_automatically()?;
-        }
+        // Create or upgrade the schema; refuses databases from a newer version of the app
+        migrations::migrate(&mut conn)?;
         
-        Ok(db)
+        Ok(Database { conn })
     }
 
-    fn init_tables(&self) -> Result<()> {
-        
-        // Users table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT UNIQUE NOT NULL,
-                password TEXT NOT NULL,
-                firstname TEXT NOT NULL,
-                lastname TEXT NOT NULL,
-                email TEXT UNIQUE NOT NULL,
-                role TEXT NOT NULL DEFAULT 'user',
-                permissions TEXT,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            )",
-            [],
-        )?;
-
-        // Supplies table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS supplies (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                description TEXT,
-                category TEXT NOT NULL,
-                subcategory TEXT,
-                variation TEXT,
-                brand TEXT,
-                quantity INTEGER NOT NULL DEFAULT 0,
-                unit TEXT NOT NULL,
-                min_quantity INTEGER NOT NULL DEFAULT 0,
-                status TEXT NOT NULL DEFAULT 'active',
-                location TEXT,
-                supplier TEXT,
-                supplier_name TEXT,
-                supplier_contact TEXT,
-                supplier_notes TEXT,
-                cost REAL,
-                pieces_per_bulk INTEGER DEFAULT 12,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            )",
-            [],
-        )?;
-        
-        // Add subcategory column if it doesn't exist (for existing databases)
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN subcategory TEXT", []);
-        // Add pieces_per_bulk column if it doesn't exist (for existing databases)
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN pieces_per_bulk INTEGER DEFAULT 12", []);
-        // Add separate supplier columns if they don't exist (for existing databases)
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN supplier_name TEXT", []);
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN supplier_contact TEXT", []);
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN supplier_notes TEXT", []);
-        // Add variation and brand columns if they don't exist (for existing databases)
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN variation TEXT", []);
-        let _ = self.conn.execute("ALTER TABLE supplies ADD COLUMN brand TEXT", []);
-
-        // Supply history table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS supply_histories (
-                id TEXT PRIMARY KEY,
-                supply_id TEXT NOT NULL,
-                action TEXT NOT NULL,
-                quantity INTEGER NOT NULL,
-                previous_quantity INTEGER NOT NULL,
-                new_quantity INTEGER NOT NULL,
-                notes TEXT,
-                user_id TEXT NOT NULL,
-                created_at TEXT NOT NULL
-            )",
-            [],
-        )?;
-
-        // Password reset tokens table
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS password_reset_tokens (
-                id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                token TEXT UNIQUE NOT NULL,
-                expires_at TEXT NOT NULL,
-                used BOOLEAN NOT NULL DEFAULT 0,
-                created_at TEXT NOT NULL
-            )",
-            [],
-        )?;
-
-        Ok(())
+    // Run `f` in a transaction: everything it writes is committed together, or rolled
+    // back when it returns an error. Nested calls join the outer transaction.
+    fn transaction<T>(&self, f: impl FnOnce(&Self) -> AppResult<T>) -> AppResult<T> {
+        if !self.conn.is_autocommit() {
+            return f(self);
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
     }
 
     // User operations
-    pub fn get_users(&self) -> Result<Vec<User>> {
+    pub fn get_users(&self) -> AppResult<Vec<User>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at 
              FROM users ORDER BY created_at DESC"
@@ -270,13 +472,13 @@ impl Database {
                 updated_at: row.get(9)?,
             })
         })?
-        .collect::<Result<Vec<_>>>()?;
+        .collect::<rusqlite::Result<Vec<_>>>()?;
 
         Ok(users)
     }
 
     // Simple user lookup (no complex optimizations)
-    pub fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+    pub fn get_user_by_username(&self, username: &str) -> AppResult<Option<User>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at 
              FROM users WHERE username = ?"
@@ -300,7 +502,7 @@ impl Database {
         Ok(user)
     }
 
-    pub fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>> {
+    pub fn get_user_by_id(&self, user_id: &str) -> AppResult<Option<User>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at 
              FROM users WHERE id = ?"
@@ -324,60 +526,76 @@ impl Database {
         Ok(user)
     }
 
-    pub fn create_user(&self, user_data: &User, created_by_user_id: &str) -> Result<String> {
-        let hashed_password = hash(&user_data.password, DEFAULT_COST)
-            .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+    // `user_data.password` is the bcrypt hash, computed by the caller outside the writer
+    pub fn create_user(&self, user_data: &User, created_by_user_id: &str, must_change_password: bool) -> AppResult<String> {
         let now = chrono::Utc::now().to_rfc3339();
         
-        self.conn.execute(
-            "INSERT INTO users (id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at) 
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            params![
-                user_data.id,
-                user_data.username,
-                hashed_password,
-                user_data.firstname,
-                user_data.lastname,
-                user_data.email,
-                user_data.role,
-                user_data.permissions,
-                now,
-                now
-            ],
-        )?;
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT INTO users (id, username, password, firstname, lastname, email, role, permissions, must_change_password, created_at, updated_at) 
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    user_data.id,
+                    user_data.username,
+                    user_data.password,
+                    user_data.firstname,
+                    user_data.lastname,
+                    user_data.email,
+                    user_data.role,
+                    user_data.permissions,
+                    must_change_password,
+                    now,
+                    now
+                ],
+            )?;
 
-        // Create a history record for user creation
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: "SYSTEM".to_string(), // Use SYSTEM as supply_id for user operations
-            action: "USER_CREATED".to_string(),
-            quantity: 0,
-            previous_quantity: 0,
-            new_quantity: 0,
-            notes: Some(format!("User '{}' created by admin", user_data.username)),
-            user_id: created_by_user_id.to_string(),
-            created_at: now.clone(),
-        };
-        
-        self.create_supply_history(&history)?;
+            db.record_audit_event(
+                created_by_user_id,
+                "USER_CREATED",
+                AUDIT_TARGET_USER,
+                Some(&user_data.id),
+                &serde_json::json!({ "username": user_data.username, "role": user_data.role }),
+            )
+        })?;
 
         Ok(user_data.id.clone())
     }
 
-    pub fn verify_password(&self, username: &str, password: &str) -> Result<bool> {
-        if let Some(user) = self.get_user_by_username(username)? {
-            let is_valid = verify(password, &user.password)
-                .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
-            Ok(is_valid)
-        } else {
-            Ok(false)
-        }
+    pub fn get_must_change_password(&self, user_id: &str) -> AppResult<bool> {
+        Ok(self.conn.query_row(
+            "SELECT must_change_password FROM users WHERE id = ?",
+            params![user_id],
+            |row| row.get(0)
+        )?)
+    }
+
+    pub fn set_must_change_password(&self, user_id: &str, must_change: bool) -> AppResult<()> {
+        self.conn.execute(
+            "UPDATE users SET must_change_password = ? WHERE id = ?",
+            params![must_change, user_id],
+        )?;
+        Ok(())
     }
 
-    pub fn get_user_by_email(&self, email: &str) -> Result<Option<User>> {
+    // The current hash and up to `depth` previous ones, to check a new password against
+    pub fn recent_password_hashes(&self, user_id: &str, depth: usize) -> AppResult<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT password FROM users WHERE id = ?1
+             UNION ALL
+             SELECT password_hash FROM (
+                 SELECT password_hash FROM password_history WHERE user_id = ?1 ORDER BY created_at DESC LIMIT ?2
+             )"
+        )?;
+        
+        let hashes = stmt.query_map(params![user_id, depth as i64], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(hashes)
+    }
+
+    pub fn get_user_by_email(&self, email: &str) -> AppResult<Option<User>> {
         let mut stmt = self.conn.prepare(
             "SELECT id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at 
-             FROM users WHERE email = ?"
+             FROM users WHERE email = ? COLLATE NOCASE"
         )?;
         
         let user = stmt.query_row(params![email], |row| {
@@ -398,55 +616,58 @@ impl Database {
         Ok(user)
     }
 
-    pub fn update_user_password(&self, user_id: &str, new_password: &str) -> Result<()> {
+    pub fn update_user_password(&self, user_id: &str, new_password: &str) -> AppResult<()> {
         let now = chrono::Utc::now().to_rfc3339();
         
-        self.conn.execute(
-            "UPDATE users SET password = ?, updated_at = ? WHERE id = ?",
-            params![new_password, now, user_id],
-        )?;
+        self.transaction(|db| {
+            // Keep the outgoing hash so it can't be reused later
+            db.conn.execute(
+                "INSERT INTO password_history (id, user_id, password_hash, created_at)
+                 SELECT ?, id, password, ? FROM users WHERE id = ?",
+                params![uuid::Uuid::new_v4().to_string(), now, user_id],
+            )?;
+            
+            db.conn.execute(
+                "UPDATE users SET password = ?, updated_at = ? WHERE id = ?",
+                params![new_password, now, user_id],
+            )?;
 
-        Ok(())
+            Ok(())
+        })
     }
 
-
-
-    pub fn delete_user(&self, user_id: &str, deleted_by_user_id: &str) -> Result<()> {
+    pub fn delete_user(&self, user_id: &str, deleted_by_user_id: &str) -> AppResult<()> {
         // Get the user being deleted for history
         let user_being_deleted = self.get_user_by_id(user_id)?;
         let username = if let Some(user) = user_being_deleted {
             user.username
         } else {
-            return Err(rusqlite::Error::InvalidParameterName("User not found".to_string()));
-        };
-        
-        self.conn.execute(
-            "DELETE FROM users WHERE id = ?",
-            params![user_id],
-        )?;
-        
-        // Create a history record for user deletion
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: "SYSTEM".to_string(), // Use SYSTEM as supply_id for user operations
-            action: "USER_DELETED".to_string(),
-            quantity: 0,
-            previous_quantity: 0,
-            new_quantity: 0,
-            notes: Some(format!("User '{}' deleted by admin", username)),
-            user_id: deleted_by_user_id.to_string(),
-            created_at: chrono::Utc::now().to_rfc3339(),
+            return Err(AppError::not_found("User not found"));
         };
         
-        self.create_supply_history(&history)?;
-        Ok(())
+        self.transaction(|db| {
+            db.conn.execute(
+                "DELETE FROM users WHERE id = ?",
+                params![user_id],
+            )?;
+            // Sessions, reset tokens, recovery codes and digest subscriptions go with the user
+            // (ON DELETE CASCADE); history rows keep the snapshotted name
+            
+            db.record_audit_event(
+                deleted_by_user_id,
+                "USER_DELETED",
+                AUDIT_TARGET_USER,
+                Some(user_id),
+                &serde_json::json!({ "username": username }),
+            )
+        })
     }
 
     // Supply operations
     // Simple supplies query
-    pub fn get_supplies(&self) -> Result<Vec<Supply>> {
+    pub fn get_supplies(&self) -> AppResult<Vec<Supply>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at 
+            "SELECT id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at, version 
              FROM supplies ORDER BY updated_at DESC"
         )?;
         
@@ -472,77 +693,87 @@ impl Database {
                 pieces_per_bulk: row.get(17)?,
                 created_at: row.get(18)?,
                 updated_at: row.get(19)?,
+                version: row.get(20)?,
             })
         })?
-        .collect::<Result<Vec<_>>>()?;
+        .collect::<rusqlite::Result<Vec<_>>>()?;
 
         Ok(supplies)
     }
 
-    pub fn create_supply(&self, supply_data: &Supply, created_by_user_id: &str) -> Result<String> {
+    pub fn create_supply(&self, supply_data: &Supply, created_by_user_id: &str) -> AppResult<String> {
         let now = chrono::Utc::now().to_rfc3339();
         
-        self.conn.execute(
-            "INSERT INTO supplies (id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at) 
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            params![
-                supply_data.id,
-                supply_data.name,
-                supply_data.description,
-                supply_data.category,
-                supply_data.subcategory,
-                supply_data.variation,
-                supply_data.brand,
-                supply_data.quantity,
-                supply_data.unit,
-                supply_data.min_quantity,
-                supply_data.status,
-                supply_data.location,
-                supply_data.supplier,
-                supply_data.supplier_name,
-                supply_data.supplier_contact,
-                supply_data.supplier_notes,
-                supply_data.cost,
-                supply_data.pieces_per_bulk,
-                now,
-                now
-            ],
-        )?;
+        self.transaction(|db| {
+            db.conn.execute(
+                "INSERT INTO supplies (id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at) 
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    supply_data.id,
+                    supply_data.name,
+                    supply_data.description,
+                    supply_data.category,
+                    supply_data.subcategory,
+                    supply_data.variation,
+                    supply_data.brand,
+                    supply_data.quantity,
+                    supply_data.unit,
+                    supply_data.min_quantity,
+                    supply_data.status,
+                    supply_data.location,
+                    supply_data.supplier,
+                    supply_data.supplier_name,
+                    supply_data.supplier_contact,
+                    supply_data.supplier_notes,
+                    supply_data.cost,
+                    supply_data.pieces_per_bulk,
+                    now,
+                    now
+                ],
+            )?;
 
-        // Create a history record for supply creation
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: supply_data.id.clone(),
-            action: "ITEM_CREATED".to_string(),
-            quantity: supply_data.quantity,
-            previous_quantity: 0,
-            new_quantity: supply_data.quantity,
-            notes: Some(format!("Item '{}' added to inventory", supply_data.name)),
-            user_id: created_by_user_id.to_string(),
-            created_at: now.clone(),
-        };
-        
-        self.create_supply_history(&history)?;
+            // Create a history record for supply creation
+            let history = SupplyHistory {
+                id: uuid::Uuid::new_v4().to_string(),
+                supply_id: supply_data.id.clone(),
+                action: "ITEM_CREATED".to_string(),
+                quantity: supply_data.quantity,
+                previous_quantity: 0,
+                new_quantity: supply_data.quantity,
+                notes: Some(format!("Item '{}' added to inventory", supply_data.name)),
+                user_id: created_by_user_id.to_string(),
+                created_at: now.clone(),
+            };
+            
+            db.create_supply_history(&history)
+        })?;
 
         Ok(supply_data.id.clone())
     }
 
     // Supply history operations
     // Simple supply histories query
-    pub fn get_supply_histories(&self) -> Result<Vec<EnrichedSupplyHistory>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT sh.id, sh.supply_id, s.name, sh.action, sh.quantity, sh.previous_quantity, sh.new_quantity, sh.notes, sh.user_id, 
-                    CASE 
-                        WHEN u.firstname IS NOT NULL AND u.lastname IS NOT NULL 
-                        THEN u.firstname || ' ' || u.lastname 
-                        ELSE u.username 
-                    END as user_name, 
-                    sh.created_at 
+    // History outlives the supplies and users it refers to: names fall back to the
+    // snapshot taken when the row was written
+    pub fn get_supply_histories(&self) -> AppResult<Vec<EnrichedSupplyHistory>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT sh.id, sh.supply_id, COALESCE(s.name, sh.supply_name, '{}'), sh.action, sh.quantity, sh.previous_quantity, sh.new_quantity, sh.notes, sh.user_id, 
+                    COALESCE(
+                        CASE 
+                            WHEN u.firstname IS NOT NULL AND u.lastname IS NOT NULL 
+                            THEN u.firstname || ' ' || u.lastname 
+                            ELSE u.username 
+                        END,
+                        sh.user_name,
+                        '{}'
+                    ) as user_name, 
+                    sh.created_at, sh.reason_code, sh.reference_number, sh.department 
              FROM supply_histories sh 
-             JOIN supplies s ON sh.supply_id = s.id 
-             JOIN users u ON sh.user_id = u.id 
-             ORDER BY sh.created_at DESC"
-        )?;
+             LEFT JOIN supplies s ON sh.supply_id = s.id 
+             LEFT JOIN users u ON sh.user_id = u.id 
+             ORDER BY sh.created_at DESC",
+            DELETED_SUPPLY_NAME, DELETED_USER_NAME
+        ))?;
         
         let histories = stmt.query_map([], |row| {
             Ok(EnrichedSupplyHistory {
@@ -557,19 +788,26 @@ impl Database {
                 user_id: row.get(8)?,
                 user_name: row.get(9)?,
                 created_at: row.get(10)?,
+                reason_code: row.get(11)?,
+                reference_number: row.get(12)?,
+                department: row.get(13)?,
             })
         })?
-        .collect::<Result<Vec<_>>>()?;
+        .collect::<rusqlite::Result<Vec<_>>>()?;
 
         Ok(histories)
     }
 
-    pub fn create_supply_history(&self, history_data: &SupplyHistory) -> Result<String> {
+    pub fn create_supply_history(&self, history_data: &SupplyHistory) -> AppResult<String> {
         let now = chrono::Utc::now().to_rfc3339();
         
+        // Supply and user names are snapshotted so the row still reads after either is deleted
         self.conn.execute(
-            "INSERT INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at) 
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at, supply_name, user_name) 
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                     (SELECT name FROM supplies WHERE id = ?2),
+                     (SELECT CASE WHEN firstname IS NOT NULL AND lastname IS NOT NULL THEN firstname || ' ' || lastname ELSE username END
+                      FROM users WHERE id = ?8))",
             params![
                 history_data.id,
                 history_data.supply_id,
@@ -586,9 +824,7 @@ impl Database {
         Ok(history_data.id.clone())
     }
 
-
-
-    pub fn update_user(&self, user_id: &str, request: &UpdateUserRequest, updated_by_user_id: &str) -> Result<()> {
+    pub fn update_user(&self, user_id: &str, request: &UpdateUserRequest, updated_by_user_id: &str) -> AppResult<()> {
         let now = chrono::Utc::now().to_rfc3339();
         
         // Get the user being updated for history
@@ -599,39 +835,48 @@ impl Database {
             "Unknown".to_string()
         };
         
-        self.conn.execute(
-            "UPDATE users SET firstname = ?, lastname = ?, username = ?, email = ?, role = ?, permissions = ?, updated_at = ? WHERE id = ?",
-            params![
-                request.firstname,
-                request.lastname,
-                request.username,
-                request.email,
-                request.role,
-                request.permissions,
-                now,
-                user_id
-            ],
-        )?;
-        
-        // Create a history record for user update
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: "SYSTEM".to_string(), // Use SYSTEM as supply_id for user operations
-            action: "USER_UPDATED".to_string(),
-            quantity: 0,
-            previous_quantity: 0,
-            new_quantity: 0,
-            notes: Some(format!("User '{}' updated by admin", username)),
-            user_id: updated_by_user_id.to_string(),
-            created_at: now.clone(),
-        };
-        
-        self.create_supply_history(&history)?;
-        Ok(())
+        self.transaction(|db| {
+            db.conn.execute(
+                "UPDATE users SET firstname = ?, lastname = ?, username = ?, email = ?, role = ?, permissions = ?, updated_at = ? WHERE id = ?",
+                params![
+                    request.firstname,
+                    request.lastname,
+                    request.username,
+                    request.email,
+                    request.role,
+                    request.permissions,
+                    now,
+                    user_id
+                ],
+            )?;
+            
+            db.record_audit_event(
+                updated_by_user_id,
+                "USER_UPDATED",
+                AUDIT_TARGET_USER,
+                Some(user_id),
+                &serde_json::json!({
+                    "username": request.username,
+                    "previous_username": username,
+                    "email": request.email,
+                    "role": request.role,
+                }),
+            )
+        })
     }
 
     // Public methods for seeding data
-    pub fn insert_user(&self, id: &str, username: &str, password: &str, firstname: &str, lastname: &str, email: &str, role: &str, permissions: &str, created_at: &str, updated_at: &str) -> Result<()> {
+    // Whether another account already uses this address, ignoring case
+    pub fn email_in_use(&self, email: &str, except_user_id: Option<&str>) -> AppResult<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM users WHERE email = ?1 COLLATE NOCASE AND (?2 IS NULL OR id <> ?2)",
+            params![email.trim(), except_user_id],
+            |row| row.get(0)
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn insert_user(&self, id: &str, username: &str, password: &str, firstname: &str, lastname: &str, email: &str, role: &str, permissions: &str, created_at: &str, updated_at: &str) -> AppResult<()> {
         self.conn.execute(
             "INSERT OR IGNORE INTO users (id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at) 
              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
@@ -640,7 +885,7 @@ impl Database {
         Ok(())
     }
 
-    pub fn insert_supply(&self, id: &str, name: &str, description: Option<&str>, category: &str, subcategory: Option<&str>, variation: Option<&str>, brand: Option<&str>, quantity: i32, unit: &str, min_quantity: i32, status: &str, location: Option<&str>, supplier: Option<&str>, supplier_name: Option<&str>, supplier_contact: Option<&str>, supplier_notes: Option<&str>, cost: Option<f64>, pieces_per_bulk: Option<i32>, created_at: &str, updated_at: &str) -> Result<()> {
+    pub fn insert_supply(&self, id: &str, name: &str, description: Option<&str>, category: &str, subcategory: Option<&str>, variation: Option<&str>, brand: Option<&str>, quantity: i32, unit: &str, min_quantity: i32, status: &str, location: Option<&str>, supplier: Option<&str>, supplier_name: Option<&str>, supplier_contact: Option<&str>, supplier_notes: Option<&str>, cost: Option<f64>, pieces_per_bulk: Option<i32>, created_at: &str, updated_at: &str) -> AppResult<()> {
         self.conn.execute(
             "INSERT OR IGNORE INTO supplies (id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at) 
              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
@@ -649,16 +894,19 @@ impl Database {
         Ok(())
     }
 
-    pub fn insert_supply_history(&self, id: &str, supply_id: &str, action: &str, quantity: i32, previous_quantity: i32, new_quantity: i32, notes: Option<&str>, user_id: &str, created_at: &str) -> Result<()> {
+    pub fn insert_supply_history(&self, id: &str, supply_id: &str, action: &str, quantity: i32, previous_quantity: i32, new_quantity: i32, notes: Option<&str>, user_id: &str, created_at: &str) -> AppResult<()> {
         self.conn.execute(
-            "INSERT OR IGNORE INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at) 
-             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            "INSERT OR IGNORE INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at, supply_name, user_name) 
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                     (SELECT name FROM supplies WHERE id = ?2),
+                     (SELECT CASE WHEN firstname IS NOT NULL AND lastname IS NOT NULL THEN firstname || ' ' || lastname ELSE username END
+                      FROM users WHERE id = ?8))",
             params![id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at],
         )?;
         Ok(())
     }
 
-    pub fn get_supply_id_by_name(&self, name: &str) -> Result<String> {
+    pub fn get_supply_id_by_name(&self, name: &str) -> AppResult<String> {
         let supply_id: String = self.conn.query_row(
             "SELECT id FROM supplies WHERE name = ?",
             params![name],
@@ -667,28 +915,30 @@ impl Database {
         Ok(supply_id)
     }
 
-    pub fn get_supply_quantity(&self, supply_id: &str) -> Result<i32> {
+    pub fn get_supply_quantity(&self, supply_id: &str) -> AppResult<i32> {
         let quantity: i32 = self.conn.query_row(
             "SELECT quantity FROM supplies WHERE id = ?",
             params![supply_id],
             |row| row.get(0)
-        )?;
+        )
+        .optional()?
+        .ok_or_else(|| AppError::not_found("Supply not found"))?;
         Ok(quantity)
     }
 
-    pub fn update_supply_quantity(&self, supply_id: &str, new_quantity: i32, updated_at: &str) -> Result<()> {
+    pub fn update_supply_quantity(&self, supply_id: &str, new_quantity: i32, updated_at: &str) -> AppResult<()> {
         // Ensure quantity is never negative
         let safe_quantity = std::cmp::max(0, new_quantity);
         self.conn.execute(
-            "UPDATE supplies SET quantity = ?, updated_at = ? WHERE id = ?",
+            "UPDATE supplies SET quantity = ?, updated_at = ?, version = version + 1 WHERE id = ?",
             params![safe_quantity, updated_at, supply_id],
         )?;
         Ok(())
     }
 
-    pub fn get_supply_by_id(&self, supply_id: &str) -> Result<Supply> {
+    pub fn get_supply_by_id(&self, supply_id: &str) -> AppResult<Supply> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at 
+            "SELECT id, name, description, category, subcategory, variation, brand, quantity, unit, min_quantity, status, location, supplier, supplier_name, supplier_contact, supplier_notes, cost, pieces_per_bulk, created_at, updated_at, version 
              FROM supplies WHERE id = ?"
         )?;
         
@@ -714,13 +964,19 @@ impl Database {
                 pieces_per_bulk: row.get(17)?,
                 created_at: row.get(18)?,
                 updated_at: row.get(19)?,
+                version: row.get(20)?,
             })
-        })?;
+        })
+        .optional()?
+        .ok_or_else(|| AppError::not_found("Supply not found"))?;
 
         Ok(supply)
     }
 
-    pub fn update_supply(&self, supply_id: &str, request: &UpdateSupplyRequest) -> Result<()> {
+    // Updates the supply and records the change in its history, both or neither.
+    // Fails with a conflict carrying the current row if the supply was changed after
+    // the version the request was based on.
+    pub fn update_supply(&self, supply_id: &str, request: &UpdateSupplyRequest, updated_by_user_id: &str) -> AppResult<()> {
         let now = chrono::Utc::now().to_rfc3339();
         
         // Build dynamic UPDATE query based on provided fields
@@ -812,9 +1068,10 @@ impl Database {
             params.push(Box::new(*pieces_per_bulk));
         }
         
-        // Always update the updated_at timestamp
+        // Always update the updated_at timestamp and the version
         query_parts.push("updated_at = ?");
         params.push(Box::new(now.clone()));
+        query_parts.push("version = version + 1");
         
         // Add the supply_id for the WHERE clause
         params.push(Box::new(supply_id.to_string()));
@@ -827,103 +1084,266 @@ impl Database {
         // Convert params to the format expected by rusqlite
         let params_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
         
-        self.conn.execute(&query, rusqlite::params_from_iter(params_refs))?;
-        Ok(())
+        // Check if item details were modified (excluding quantity)
+        let item_details_changed = 
+            request.name.is_some() || 
+            request.description.is_some() || 
+            request.category.is_some() || 
+            request.subcategory.is_some() ||
+            request.variation.is_some() ||
+            request.brand.is_some() ||
+            request.unit.is_some() || 
+            request.min_quantity.is_some() || 
+            request.status.is_some() || 
+            request.location.is_some() || 
+            request.supplier.is_some() || 
+            request.cost.is_some();
+        
+        self.transaction(|db| {
+            // Get current supply to calculate quantity changes
+            let current = db.get_supply_by_id(supply_id)?;
+            if current.version != request.version {
+                return Err(supply_conflict(current));
+            }
+            let current_quantity = current.quantity;
+            let new_quantity = request.quantity.unwrap_or(current_quantity);
+            
+            db.conn.execute(&query, rusqlite::params_from_iter(params_refs))?;
+            
+            // If quantity changed, create a stock history record
+            if new_quantity != current_quantity {
+                let action = if new_quantity > current_quantity { "Stock In" } else { "Stock Out" };
+                let quantity_change = (new_quantity - current_quantity).abs();
+                
+                // Determine the appropriate reason based on the action
+                let notes = if new_quantity > current_quantity {
+                    request.stock_in_reason.clone().unwrap_or_else(|| "Stock added".to_string())
+                } else {
+                    request.stock_out_reason.clone().unwrap_or_else(|| "Stock released".to_string())
+                };
+                
+                let history = SupplyHistory {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    supply_id: supply_id.to_string(),
+                    action: action.to_string(),
+                    quantity: quantity_change,
+                    previous_quantity: current_quantity,
+                    new_quantity,
+                    notes: Some(notes),
+                    user_id: updated_by_user_id.to_string(),
+                    created_at: now.clone(),
+                };
+                
+                db.create_supply_history(&history)?;
+            }
+            // If only item details changed (no quantity change), create an "Item Updated" history record
+            else if item_details_changed {
+                let history = SupplyHistory {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    supply_id: supply_id.to_string(),
+                    action: "Item Updated".to_string(),
+                    quantity: 0,
+                    previous_quantity: current_quantity,
+                    new_quantity: current_quantity,
+                    notes: Some("Item details updated".to_string()),
+                    user_id: updated_by_user_id.to_string(),
+                    created_at: now.clone(),
+                };
+                
+                db.create_supply_history(&history)?;
+            }
+            
+            Ok(())
+        })
     }
 
-    pub fn delete_supply(&self, supply_id: &str, user_id: &str) -> Result<()> {
-        // First, create a history record for the deletion
-        let supply = self.get_supply_by_id(supply_id)?;
-        let now = chrono::Utc::now().to_rfc3339();
-        
-        // Get current user ID for history record
-        let current_user = self.get_user_by_id(user_id)?;
-        let user_id_for_history = if let Some(user) = current_user {
-            user.id
-        } else {
-            return Err(rusqlite::Error::InvalidParameterName("Current user not found".to_string()));
-        };
-        
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: supply_id.to_string(),
-            action: "Delete".to_string(),
-            quantity: supply.quantity,
-            previous_quantity: supply.quantity,
-            new_quantity: 0,
-            notes: Some("Item permanently removed from inventory".to_string()),
-            user_id: user_id_for_history,
-            created_at: now.clone(),
+    // Apply a stock movement and record it in the history, both or neither. Returns the
+    // supply as it is afterwards.
+    pub fn apply_stock_movement(&self, movement: StockMovement, request: &StockMovementRequest, user_id: &str) -> AppResult<Supply> {
+        let delta = match movement {
+            StockMovement::In | StockMovement::Out if request.quantity <= 0 => {
+                return Err(AppError::validation("Quantity must be greater than zero"));
+            }
+            StockMovement::Adjust if request.quantity == 0 => {
+                return Err(AppError::validation("An adjustment must change the quantity"));
+            }
+            _ if request.quantity.unsigned_abs() > MAX_STOCK_MOVEMENT as u32 => {
+                return Err(AppError::validation(format!("A single movement can't move more than {}", MAX_STOCK_MOVEMENT)));
+            }
+            StockMovement::Out => -request.quantity,
+            _ => request.quantity,
         };
         
-        self.create_supply_history(&history)?;
-        
-        // Delete the supply
-        self.conn.execute(
-            "DELETE FROM supplies WHERE id = ?",
-            params![supply_id]
-        )?;
+        let reason_code = request.reason_code.trim();
+        if !movement.reason_codes().contains(&reason_code) {
+            return Err(AppError::validation(format!(
+                "Unknown reason code '{}', expected one of: {}",
+                reason_code,
+                movement.reason_codes().join(", ")
+            )));
+        }
+        let reference_number = request.reference_number.trim();
+        if reference_number.is_empty() {
+            return Err(AppError::validation("A reference number is required"));
+        }
+        let department = request.department.as_deref().map(str::trim).filter(|d| !d.is_empty());
+        let notes = request.notes.as_deref().map(str::trim).filter(|n| !n.is_empty());
         
-        Ok(())
+        self.transaction(|db| {
+            let now = chrono::Utc::now().to_rfc3339();
+            
+            // Check and change in one statement, relative to whatever the quantity is now,
+            // so no other movement can slip in between and take the stock below zero
+            let new_quantity: Option<i32> = db.conn.query_row(
+                "UPDATE supplies SET quantity = quantity + ?1, updated_at = ?2, version = version + 1
+                 WHERE id = ?3 AND quantity + ?1 BETWEEN 0 AND ?4
+                 RETURNING quantity",
+                params![delta, now, request.supply_id, i32::MAX],
+                |row| row.get(0)
+            ).optional()?;
+            
+            let new_quantity = match new_quantity {
+                Some(quantity) => quantity,
+                None => {
+                    let available = db.get_supply_quantity(&request.supply_id)?;
+                    if delta > 0 {
+                        return Err(AppError::validation(format!("Quantity can't go above {}", i32::MAX)));
+                    }
+                    return Err(AppError::conflict(format!("Not enough stock: {} available", available))
+                        .with_details(serde_json::json!({ "available": available })));
+                }
+            };
+            
+            let supply = db.get_supply_by_id(&request.supply_id)?;
+            let status = db.calculate_stock_status(supply.quantity, supply.min_quantity);
+            db.conn.execute(
+                "UPDATE supplies SET status = ? WHERE id = ?",
+                params![status, request.supply_id],
+            )?;
+            
+            db.conn.execute(
+                "INSERT INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at, supply_name, user_name, reason_code, reference_number, department) 
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9,
+                         (SELECT name FROM supplies WHERE id = ?2),
+                         (SELECT CASE WHEN firstname IS NOT NULL AND lastname IS NOT NULL THEN firstname || ' ' || lastname ELSE username END
+                          FROM users WHERE id = ?8),
+                         ?10, ?11, ?12)",
+                params![
+                    uuid::Uuid::new_v4().to_string(),
+                    request.supply_id,
+                    movement.action(),
+                    delta.unsigned_abs(),
+                    new_quantity - delta,
+                    new_quantity,
+                    notes,
+                    user_id,
+                    now,
+                    reason_code,
+                    reference_number,
+                    department
+                ],
+            )?;
+            
+            Ok(Supply { status, ..supply })
+        })
     }
 
-    pub fn delete_supply_history(&self, history_id: &str, deleted_by_user_id: &str) -> Result<()> {
-        // Get the history record being deleted for logging
-        let mut stmt = self.conn.prepare(
-            "SELECT sh.id, sh.supply_id, s.name, sh.action, sh.notes 
-             FROM supply_histories sh 
-             LEFT JOIN supplies s ON sh.supply_id = s.id 
-             WHERE sh.id = ?"
-        )?;
-        
-        let history_info = stmt.query_row(params![history_id], |row| {
-            Ok((
-                row.get::<_, String>(0)?, // id
-                row.get::<_, String>(1)?, // supply_id
-                row.get::<_, Option<String>>(2)?, // supply_name
-                row.get::<_, String>(3)?, // action
-                row.get::<_, Option<String>>(4)?, // notes
-            ))
-        }).optional()?;
-        
-        let (_history_id_str, supply_id, supply_name, action, _notes) = if let Some(info) = history_info {
-            info
-        } else {
-            return Err(rusqlite::Error::InvalidParameterName("History record not found".to_string()));
-        };
-        
-        self.conn.execute(
-            "DELETE FROM supply_histories WHERE id = ?",
-            params![history_id]
-        )?;
-        
-        // Create a history record for history deletion
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: "SYSTEM".to_string(), // Use SYSTEM as supply_id for system operations
-            action: "HISTORY_DELETED".to_string(),
-            quantity: 0,
-            previous_quantity: 0,
-            new_quantity: 0,
-            notes: Some(format!("History record for '{}' ({}) deleted", 
-                supply_name.unwrap_or_else(|| supply_id.clone()), 
-                action)),
-            user_id: deleted_by_user_id.to_string(),
-            created_at: chrono::Utc::now().to_rfc3339(),
-        };
-        
-        self.create_supply_history(&history)?;
-        
-        Ok(())
+    pub fn delete_supply(&self, supply_id: &str, user_id: &str) -> AppResult<()> {
+        self.transaction(|db| {
+            // First, create a history record for the deletion
+            let supply = db.get_supply_by_id(supply_id)?;
+            let now = chrono::Utc::now().to_rfc3339();
+            
+            // Get current user ID for history record
+            let current_user = db.get_user_by_id(user_id)?;
+            let user_id_for_history = if let Some(user) = current_user {
+                user.id
+            } else {
+                return Err(AppError::not_found("Current user not found"));
+            };
+            
+            let history = SupplyHistory {
+                id: uuid::Uuid::new_v4().to_string(),
+                supply_id: supply_id.to_string(),
+                action: "Delete".to_string(),
+                quantity: supply.quantity,
+                previous_quantity: supply.quantity,
+                new_quantity: 0,
+                notes: Some("Item permanently removed from inventory".to_string()),
+                user_id: user_id_for_history,
+                created_at: now.clone(),
+            };
+            
+            db.create_supply_history(&history)?;
+            
+            // Delete the supply
+            db.conn.execute(
+                "DELETE FROM supplies WHERE id = ?",
+                params![supply_id]
+            )?;
+            
+            Ok(())
+        })
     }
 
+    pub fn delete_supply_history(&self, history_id: &str, deleted_by_user_id: &str) -> AppResult<()> {
+        self.transaction(|db| {
+            // Get the history record being deleted for logging
+            let mut stmt = db.conn.prepare(
+                "SELECT sh.id, sh.supply_id, COALESCE(s.name, sh.supply_name), sh.action, sh.notes 
+                 FROM supply_histories sh 
+                 LEFT JOIN supplies s ON sh.supply_id = s.id 
+                 WHERE sh.id = ?"
+            )?;
+            
+            let history_info = stmt.query_row(params![history_id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?, // id
+                    row.get::<_, Option<String>>(1)?, // supply_id
+                    row.get::<_, Option<String>>(2)?, // supply_name
+                    row.get::<_, String>(3)?, // action
+                    row.get::<_, Option<String>>(4)?, // notes
+                ))
+            }).optional()?;
+            
+            let (_history_id_str, supply_id, supply_name, action, notes) = if let Some(info) = history_info {
+                info
+            } else {
+                return Err(AppError::not_found("History record not found"));
+            };
+            
+            db.conn.execute(
+                "DELETE FROM supply_histories WHERE id = ?",
+                params![history_id]
+            )?;
+            
+            db.record_audit_event(
+                deleted_by_user_id,
+                "HISTORY_DELETED",
+                AUDIT_TARGET_SUPPLY_HISTORY,
+                Some(history_id),
+                &serde_json::json!({
+                    "supply_id": supply_id,
+                    "supply_name": supply_name,
+                    "action": action,
+                    "notes": notes,
+                }),
+            )?;
+            
+            Ok(())
+        })
+    }
 
+    pub fn count_users(&self) -> AppResult<i64> {
+        Ok(self.conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))?)
+    }
 
-    // Clear ALL data except admin user
-
+    pub fn count_supplies(&self) -> AppResult<i64> {
+        Ok(self.conn.query_row("SELECT COUNT(*) FROM supplies", [], |row| row.get(0))?)
+    }
 
     // Automatic sample data seeding on first run
-    fn seed_sample_data_automatically(&self) -> Result<()> {
+    fn seed_sample_data_automatically(&self) -> AppResult<()> {
         // Check if sample data already exists
         let sample_supply_count: i32 = self.conn.query_row(
             "SELECT COUNT(*) FROM supplies WHERE name IN ('Blue Ballpoint Pens', 'Black Markers', 'Yellow Highlighters')",
@@ -940,7 +1360,7 @@ impl Database {
     }
 
     // Internal seeding function (moved from main.rs)
-    fn seed_sample_data_internal(&self) -> Result<()> {
+    fn seed_sample_data_internal(&self) -> AppResult<()> {
         // Sample users - create them first
         let sample_users = vec![
             ("abbarcelo", "password123", "Arne B.", "Barcelo", "abbarcelo@ust.edu.ph", "admin", r#"{"users": ["view", "create", "edit", "delete"], "supplies": ["view", "create", "edit", "delete"], "supply_histories": ["view", "create", "edit", "delete"], "reports": ["view"]}"#),
@@ -951,8 +1371,7 @@ impl Database {
         
         // Create all users first
         for (username, password, firstname, lastname, email, role, permissions) in sample_users {
-            let hashed_password = bcrypt::hash(password, bcrypt::DEFAULT_COST)
-                .map_err(|e| rusqlite::Error::InvalidParameterName(format!("Failed to hash password: {}", e)))?;
+            let hashed_password = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
             let now = chrono::Utc::now().to_rfc3339();
             
             self.insert_user(
@@ -974,9 +1393,14 @@ impl Database {
         let admin_id = if let Some(user) = admin_user {
             user.id
         } else {
-            return Err(rusqlite::Error::InvalidParameterName("Admin user not found".to_string()));
+            return Err(AppError::not_found("Admin user not found"));
         };
         
+        self.seed_sample_supplies(&admin_id)
+    }
+
+    // Demo supplies and stock movements, attributed to the given admin
+    pub fn seed_sample_supplies(&self, admin_id: &str) -> AppResult<()> {
         // Sample supplies with balanced stock levels (Low, Moderate, High)
         // Format: (name, description, category, subcategory, quantity, unit, min_quantity, status, location, supplier_name, supplier_contact, supplier_notes, cost, variation, brand)
         let sample_supplies = vec![
@@ -1125,25 +1549,25 @@ impl Database {
                 0,
                 quantity,
                 Some("Initial stock"),
-                &admin_id,
+                admin_id,
                 &initial_date.to_rfc3339()
             )?;
         }
         
         // Additional supply history records for testing with realistic dates and different users
         let additional_history = vec![
-            ("A4 Bond Paper", "Stock Out", 100, "Meeting room supplies", 75, &admin_id), // 75 days ago
-            ("Blue Ballpoint Pens", "Stock Out", 24, "IT department", 60, &admin_id), // 60 days ago
-            ("Binder Clips", "Stock In", 100, "Restock order", 45, &admin_id), // 45 days ago
-            ("Black Markers", "Stock Out", 20, "Training session", 30, &admin_id), // 30 days ago
-            ("Coffee Beans", "Stock Out", 2, "Weekly consumption", 20, &admin_id), // 20 days ago
-            ("Sticky Notes", "Stock In", 24, "Emergency order", 15, &admin_id), // 15 days ago
-            ("USB Flash Drives", "Stock Out", 5, "IT department", 10, &admin_id), // 10 days ago
-            ("A4 Bond Paper", "Stock Out", 50, "Office supplies", 7, &admin_id), // 7 days ago
-            ("Blue Ballpoint Pens", "Stock In", 36, "Monthly restock", 5, &admin_id), // 5 days ago
-            ("Binder Clips", "Stock Out", 50, "Department request", 3, &admin_id), // 3 days ago
-            ("Coffee Beans", "Stock In", 3, "Weekly restock", 2, &admin_id), // 2 days ago
-            ("Black Markers", "Stock Out", 10, "New employee setup", 1, &admin_id), // 1 day ago
+            ("A4 Bond Paper", "Stock Out", 100, "Meeting room supplies", 75, admin_id), // 75 days ago
+            ("Blue Ballpoint Pens", "Stock Out", 24, "IT department", 60, admin_id), // 60 days ago
+            ("Binder Clips", "Stock In", 100, "Restock order", 45, admin_id), // 45 days ago
+            ("Black Markers", "Stock Out", 20, "Training session", 30, admin_id), // 30 days ago
+            ("Coffee Beans", "Stock Out", 2, "Weekly consumption", 20, admin_id), // 20 days ago
+            ("Sticky Notes", "Stock In", 24, "Emergency order", 15, admin_id), // 15 days ago
+            ("USB Flash Drives", "Stock Out", 5, "IT department", 10, admin_id), // 10 days ago
+            ("A4 Bond Paper", "Stock Out", 50, "Office supplies", 7, admin_id), // 7 days ago
+            ("Blue Ballpoint Pens", "Stock In", 36, "Monthly restock", 5, admin_id), // 5 days ago
+            ("Binder Clips", "Stock Out", 50, "Department request", 3, admin_id), // 3 days ago
+            ("Coffee Beans", "Stock In", 3, "Weekly restock", 2, admin_id), // 2 days ago
+            ("Black Markers", "Stock Out", 10, "New employee setup", 1, admin_id), // 1 day ago
         ];
         
         for (supply_name, action, quantity, notes, days_ago, user_id) in additional_history {
@@ -1184,56 +1608,884 @@ impl Database {
     }
 
     // Password reset token methods
-    pub fn create_password_reset_token(&self, user_id: &str, token: &str, expires_at: &str) -> Result<String> {
+    // Tokens are stored as SHA-256 hashes, see reset_tokens::hash_token
+    pub fn create_password_reset_token(&self, user_id: &str, token_hash: &str, expires_at: &str) -> AppResult<String> {
         let id = uuid::Uuid::new_v4().to_string();
         let now = chrono::Utc::now().to_rfc3339();
         
         self.conn.execute(
             "INSERT INTO password_reset_tokens (id, user_id, token, expires_at, used, created_at) VALUES (?, ?, ?, ?, 0, ?)",
-            params![id, user_id, token, expires_at, now],
+            params![id, user_id, token_hash, expires_at, now],
         )?;
         
         Ok(id)
     }
 
-    pub fn get_password_reset_token(&self, token: &str) -> Result<Option<PasswordResetToken>> {
-        let token_data = self.conn.query_row(
-            "SELECT id, user_id, token, expires_at, used, created_at FROM password_reset_tokens WHERE token = ?",
-            params![token],
+    pub fn get_password_reset_tokens_for_user(&self, user_id: &str) -> AppResult<Vec<PasswordResetToken>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, user_id, token, expires_at, used, created_at FROM password_reset_tokens WHERE user_id = ?"
+        )?;
+        
+        let token_iter = stmt.query_map(params![user_id], |row| {
+            Ok(PasswordResetToken {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                token: row.get(2)?,
+                expires_at: row.get(3)?,
+                used: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?;
+        
+        let mut tokens = Vec::new();
+        for token in token_iter {
+            tokens.push(token?);
+        }
+        
+        Ok(tokens)
+    }
+
+    // Set the password the user chose through a reset link. Every reset token they hold
+    // is used up with it, so an older link from the same inbox can't reset it again.
+    pub fn complete_password_reset(&self, user_id: &str, new_password: &str) -> AppResult<()> {
+        self.transaction(|db| {
+            db.update_user_password(user_id, new_password)?;
+            db.conn.execute(
+                "UPDATE password_reset_tokens SET used = 1 WHERE user_id = ?",
+                params![user_id],
+            )?;
+            db.set_must_change_password(user_id, false)?;
+            // Sign out everywhere once the password has changed
+            db.delete_sessions_for_user(user_id)?;
+            Ok(())
+        })
+    }
+
+    // Reset requests since `since`, for one address or across all of them.
+    // Requests that were themselves throttled don't count, so the window keeps sliding.
+    pub fn count_reset_requests(&self, email: Option<&str>, since: &str) -> AppResult<i64> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM security_events
+             WHERE event_type = 'password_reset_request' AND created_at >= ?1
+               AND (reason IS NULL OR reason <> 'throttled')
+               AND (?2 IS NULL OR lower(username) = lower(?2))",
+            params![since, email],
+            |row| row.get(0)
+        )?)
+    }
+
+    pub fn cleanup_expired_tokens(&self) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "DELETE FROM password_reset_tokens WHERE expires_at < ? OR used = 1",
+            params![now],
+        )?;
+        Ok(())
+    }
+
+    // Login throttling methods
+    // Usernames are tracked case-insensitively so casing can't be used to dodge a lockout
+    fn throttle_key(username: &str) -> String {
+        username.trim().to_lowercase()
+    }
+
+    // Returns the latest active lockout for the attempted username or its account
+    pub fn get_login_lockout(&self, username: &str, user_id: Option<&str>) -> AppResult<Option<String>> {
+        let now = chrono::Utc::now().to_rfc3339();
+        
+        let username_lock: Option<String> = self.conn.query_row(
+            "SELECT locked_until FROM login_throttles WHERE username = ? AND locked_until > ?",
+            params![Self::throttle_key(username), now],
+            |row| row.get(0)
+        ).optional()?;
+        
+        let account_lock: Option<String> = match user_id {
+            Some(user_id) => self.conn.query_row(
+                "SELECT locked_until FROM users WHERE id = ? AND locked_until > ?",
+                params![user_id, now],
+                |row| row.get(0)
+            ).optional()?,
+            None => None,
+        };
+        
+        Ok(username_lock.max(account_lock))
+    }
+
+    // Count a failed login against the username and, if it exists, the account.
+    // Returns the higher of the two consecutive failure counts.
+    pub fn record_failed_login(&self, username: &str, user_id: Option<&str>) -> AppResult<i32> {
+        let now = chrono::Utc::now().to_rfc3339();
+        
+        let username_failures: i32 = self.conn.query_row(
+            "INSERT INTO login_throttles (username, failed_attempts, updated_at) VALUES (?, 1, ?)
+             ON CONFLICT(username) DO UPDATE SET failed_attempts = failed_attempts + 1, updated_at = excluded.updated_at
+             RETURNING failed_attempts",
+            params![Self::throttle_key(username), now],
+            |row| row.get(0)
+        )?;
+        
+        let account_failures: i32 = match user_id {
+            Some(user_id) => self.conn.query_row(
+                "UPDATE users SET failed_login_attempts = failed_login_attempts + 1 WHERE id = ? RETURNING failed_login_attempts",
+                params![user_id],
+                |row| row.get(0)
+            )?,
+            None => 0,
+        };
+        
+        Ok(username_failures.max(account_failures))
+    }
+
+    pub fn lock_login(&self, username: &str, user_id: Option<&str>, locked_until: &str) -> AppResult<()> {
+        self.conn.execute(
+            "UPDATE login_throttles SET locked_until = ? WHERE username = ?",
+            params![locked_until, Self::throttle_key(username)],
+        )?;
+        
+        if let Some(user_id) = user_id {
+            self.conn.execute(
+                "UPDATE users SET locked_until = ? WHERE id = ?",
+                params![locked_until, user_id],
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    pub fn clear_failed_logins(&self, username: &str, user_id: Option<&str>) -> AppResult<()> {
+        self.conn.execute(
+            "DELETE FROM login_throttles WHERE username = ?",
+            params![Self::throttle_key(username)],
+        )?;
+        
+        if let Some(user_id) = user_id {
+            self.conn.execute(
+                "UPDATE users SET failed_login_attempts = 0, locked_until = NULL WHERE id = ?",
+                params![user_id],
+            )?;
+        }
+        
+        Ok(())
+    }
+
+    // Admin unlock: clears the account lockout and the throttle on its username
+    pub fn unlock_user(&self, user_id: &str) -> AppResult<()> {
+        let user = self.get_user_by_id(user_id)?;
+        let username = if let Some(user) = user {
+            user.username
+        } else {
+            return Err(AppError::not_found("User not found"));
+        };
+        
+        self.clear_failed_logins(&username, Some(user_id))
+    }
+
+    // Security event methods
+    pub fn record_security_event(&self, event_type: &str, username: &str, user_id: Option<&str>, outcome: &str, reason: Option<&str>) -> AppResult<()> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().to_rfc3339();
+        
+        self.conn.execute(
+            "INSERT INTO security_events (id, event_type, username, user_id, outcome, reason, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![id, event_type, username, user_id, outcome, reason, now],
+        )?;
+        
+        Ok(())
+    }
+
+    // Foreign keys are enforced, but rows can still be orphaned by editing the file
+    // with other tools. Orphans found when the keys were introduced are in the audit log.
+    pub fn check_integrity(&self) -> AppResult<IntegrityReport> {
+        let mut stmt = self.conn.prepare("PRAGMA quick_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|line| line != "ok")
+            .collect::<Vec<_>>();
+        
+        let mut stmt = self.conn.prepare(
+            "SELECT \"table\", parent, COUNT(*) FROM pragma_foreign_key_check GROUP BY \"table\", parent ORDER BY \"table\", parent"
+        )?;
+        let orphans = stmt.query_map([], |row| {
+            Ok(OrphanedRows {
+                table: row.get(0)?,
+                parent_table: row.get(1)?,
+                count: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(IntegrityReport {
+            ok: problems.is_empty() && orphans.is_empty(),
+            problems,
+            orphans,
+        })
+    }
+
+    // Audit log operations
+    pub fn record_audit_event(&self, actor_id: &str, action: &str, target_type: &str, target_id: Option<&str>, details: &serde_json::Value) -> AppResult<()> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().to_rfc3339();
+        
+        self.conn.execute(
+            "INSERT INTO audit_log (id, actor_id, actor_username, action, target_type, target_id, details, created_at)
+             VALUES (?1, ?2, (SELECT username FROM users WHERE id = ?2), ?3, ?4, ?5, ?6, ?7)",
+            params![id, actor_id, action, target_type, target_id, details.to_string(), now],
+        )?;
+        
+        Ok(())
+    }
+
+    // Background jobs have no one to report to, so their failures go to the audit log
+    pub fn record_job_error(&self, job: &str, error: &str) -> AppResult<()> {
+        self.conn.execute(
+            "INSERT INTO audit_log (id, action, target_type, target_id, details, created_at)
+             VALUES (?, 'JOB_FAILED', 'system', ?, ?, ?)",
+            params![
+                uuid::Uuid::new_v4().to_string(),
+                job,
+                serde_json::json!({ "error": error }).to_string(),
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_audit_log(&self, filter: &AuditLogFilter) -> AppResult<AuditLogPage> {
+        // Build dynamic WHERE clause based on provided filters
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+        
+        if let Some(actor) = &filter.actor {
+            conditions.push("(actor_id = ? OR actor_username LIKE ?)");
+            params.push(Box::new(actor.clone()));
+            params.push(Box::new(format!("%{}%", actor)));
+        }
+        
+        if let Some(action) = &filter.action {
+            conditions.push("action = ?");
+            params.push(Box::new(action.clone()));
+        }
+        
+        if let Some(target_type) = &filter.target_type {
+            conditions.push("target_type = ?");
+            params.push(Box::new(target_type.clone()));
+        }
+        
+        if let Some(target_id) = &filter.target_id {
+            conditions.push("target_id = ?");
+            params.push(Box::new(target_id.clone()));
+        }
+        
+        if let Some(from) = &filter.from {
+            conditions.push("created_at >= ?");
+            params.push(Box::new(from.clone()));
+        }
+        
+        if let Some(to) = &filter.to {
+            conditions.push("created_at <= ?");
+            params.push(Box::new(to.clone()));
+        }
+        
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        
+        let params_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+        
+        let total: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM audit_log {}", where_clause),
+            rusqlite::params_from_iter(params_refs.iter()),
+            |row| row.get(0)
+        )?;
+        
+        let page = filter.page.unwrap_or(1).max(1);
+        let page_size = filter.page_size.unwrap_or(50).clamp(1, 500);
+        let offset = (page as i64 - 1) * page_size as i64;
+        
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, actor_id, actor_username, action, target_type, target_id, details, created_at 
+             FROM audit_log {} ORDER BY created_at DESC LIMIT {} OFFSET {}",
+            where_clause, page_size, offset
+        ))?;
+        
+        let entries = stmt.query_map(rusqlite::params_from_iter(params_refs.iter()), |row| {
+            let details: Option<String> = row.get(6)?;
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                actor_id: row.get(1)?,
+                actor_username: row.get(2)?,
+                action: row.get(3)?,
+                target_type: row.get(4)?,
+                target_id: row.get(5)?,
+                details: details
+                    .map(|text| serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text)))
+                    .unwrap_or(serde_json::Value::Null),
+                created_at: row.get(7)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(AuditLogPage {
+            entries,
+            total,
+            page,
+            page_size,
+        })
+    }
+
+    pub fn get_security_events(&self, filter: &SecurityEventFilter) -> AppResult<SecurityEventPage> {
+        // Build dynamic WHERE clause based on provided filters
+        let mut conditions = Vec::new();
+        let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+        
+        if let Some(event_type) = &filter.event_type {
+            conditions.push("event_type = ?");
+            params.push(Box::new(event_type.clone()));
+        }
+        
+        if let Some(username) = &filter.username {
+            conditions.push("username LIKE ?");
+            params.push(Box::new(format!("%{}%", username)));
+        }
+        
+        if let Some(outcome) = &filter.outcome {
+            conditions.push("outcome = ?");
+            params.push(Box::new(outcome.clone()));
+        }
+        
+        if let Some(from) = &filter.from {
+            conditions.push("created_at >= ?");
+            params.push(Box::new(from.clone()));
+        }
+        
+        if let Some(to) = &filter.to {
+            conditions.push("created_at <= ?");
+            params.push(Box::new(to.clone()));
+        }
+        
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        
+        let params_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+        
+        let total: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM security_events {}", where_clause),
+            rusqlite::params_from_iter(params_refs.iter()),
+            |row| row.get(0)
+        )?;
+        
+        let page = filter.page.unwrap_or(1).max(1);
+        let page_size = filter.page_size.unwrap_or(50).clamp(1, 500);
+        let offset = (page as i64 - 1) * page_size as i64;
+        
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id, event_type, username, user_id, outcome, reason, created_at 
+             FROM security_events {} ORDER BY created_at DESC LIMIT {} OFFSET {}",
+            where_clause, page_size, offset
+        ))?;
+        
+        let events = stmt.query_map(rusqlite::params_from_iter(params_refs.iter()), |row| {
+            Ok(SecurityEvent {
+                id: row.get(0)?,
+                event_type: row.get(1)?,
+                username: row.get(2)?,
+                user_id: row.get(3)?,
+                outcome: row.get(4)?,
+                reason: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(SecurityEventPage {
+            events,
+            total,
+            page,
+            page_size,
+        })
+    }
+
+    // Session methods
+    // Tokens are stored as SHA-256 hashes like reset tokens; every method here takes the
+    // token itself and hashes it
+    pub fn create_session(&self, user_id: &str, token: &str, scope: &str, expires_at: &str) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        
+        self.conn.execute(
+            "INSERT INTO sessions (token, user_id, scope, expires_at, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![hash_token(token), user_id, scope, expires_at, now],
+        )?;
+        
+        Ok(())
+    }
+
+    // Resolve the user and scope behind a session token, ignoring expired sessions
+    pub fn get_session(&self, token: &str) -> AppResult<Option<(User, String)>> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let session: Option<(String, String)> = self.conn.query_row(
+            "SELECT user_id, scope FROM sessions WHERE token = ? AND expires_at > ?",
+            params![hash_token(token), now],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional()?;
+        
+        match session {
+            Some((user_id, scope)) => Ok(self.get_user_by_id(&user_id)?.map(|user| (user, scope))),
+            None => Ok(None),
+        }
+    }
+
+    // Resolve the user behind a full session token
+    pub fn get_session_user(&self, token: &str) -> AppResult<Option<User>> {
+        Ok(self.get_session(token)?
+            .filter(|(_, scope)| scope == SESSION_SCOPE_FULL)
+            .map(|(user, _)| user))
+    }
+
+    pub fn update_session_scope(&self, token: &str, scope: &str, expires_at: &str) -> AppResult<()> {
+        self.conn.execute(
+            "UPDATE sessions SET scope = ?, expires_at = ? WHERE token = ?",
+            params![scope, expires_at, hash_token(token)],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_session(&self, token: &str) -> AppResult<()> {
+        self.conn.execute(
+            "DELETE FROM sessions WHERE token = ?",
+            params![hash_token(token)],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_sessions_for_user(&self, user_id: &str) -> AppResult<usize> {
+        let deleted = self.conn.execute(
+            "DELETE FROM sessions WHERE user_id = ?",
+            params![user_id],
+        )?;
+        Ok(deleted)
+    }
+
+    // Sign a user out everywhere except the given session
+    pub fn delete_other_sessions(&self, user_id: &str, keep_token: &str) -> AppResult<usize> {
+        let deleted = self.conn.execute(
+            "DELETE FROM sessions WHERE user_id = ? AND token != ?",
+            params![user_id, hash_token(keep_token)],
+        )?;
+        Ok(deleted)
+    }
+
+    pub fn cleanup_expired_sessions(&self) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "DELETE FROM sessions WHERE expires_at < ?",
+            params![now],
+        )?;
+        Ok(())
+    }
+
+    // Two-factor methods
+    pub fn get_totp_state(&self, user_id: &str) -> AppResult<TotpState> {
+        Ok(self.conn.query_row(
+            "SELECT totp_secret, totp_enabled, totp_last_step FROM users WHERE id = ?",
+            params![user_id],
             |row| {
-                Ok(PasswordResetToken {
-                    id: row.get(0)?,
-                    user_id: row.get(1)?,
-                    token: row.get(2)?,
-                    expires_at: row.get(3)?,
-                    used: row.get(4)?,
-                    created_at: row.get(5)?,
+                Ok(TotpState {
+                    secret: row.get(0)?,
+                    enabled: row.get(1)?,
+                    last_step: row.get(2)?,
                 })
             }
+        )?)
+    }
+
+    // Store a secret awaiting confirmation; 2FA stays off until `enable_totp`
+    pub fn set_pending_totp_secret(&self, user_id: &str, secret: &str) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "UPDATE users SET totp_secret = ?, totp_enabled = 0, totp_last_step = NULL, updated_at = ? WHERE id = ?",
+            params![secret, now, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn enable_totp(&self, user_id: &str, last_step: i64) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "UPDATE users SET totp_enabled = 1, totp_last_step = ?, updated_at = ? WHERE id = ?",
+            params![last_step, now, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_totp_last_step(&self, user_id: &str, last_step: i64) -> AppResult<()> {
+        self.conn.execute(
+            "UPDATE users SET totp_last_step = ? WHERE id = ?",
+            params![last_step, user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn disable_totp(&self, user_id: &str) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL, updated_at = ? WHERE id = ?",
+            params![now, user_id],
+        )?;
+        self.conn.execute(
+            "DELETE FROM recovery_codes WHERE user_id = ?",
+            params![user_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn replace_recovery_codes(&self, user_id: &str, code_hashes: &[String]) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "DELETE FROM recovery_codes WHERE user_id = ?",
+            params![user_id],
+        )?;
+        for code_hash in code_hashes {
+            self.conn.execute(
+                "INSERT INTO recovery_codes (id, user_id, code_hash, used_at, created_at) VALUES (?, ?, ?, NULL, ?)",
+                params![uuid::Uuid::new_v4().to_string(), user_id, code_hash, now],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Marks a matching unused recovery code as used; returns whether one was found
+    pub fn consume_recovery_code(&self, user_id: &str, code_hash: &str) -> AppResult<bool> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let updated = self.conn.execute(
+            "UPDATE recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+            params![now, user_id, code_hash],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Role policy methods
+    pub fn get_role_policies(&self) -> AppResult<Vec<RolePolicy>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT role, require_2fa, updated_at FROM role_policies ORDER BY role"
+        )?;
+        
+        let policies = stmt.query_map([], |row| {
+            Ok(RolePolicy {
+                role: row.get(0)?,
+                require_2fa: row.get(1)?,
+                updated_at: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(policies)
+    }
+
+    pub fn role_requires_2fa(&self, role: &str) -> AppResult<bool> {
+        let required: Option<bool> = self.conn.query_row(
+            "SELECT require_2fa FROM role_policies WHERE role = ?",
+            params![role],
+            |row| row.get(0)
         ).optional()?;
+        Ok(required.unwrap_or(false))
+    }
+
+    pub fn set_role_requires_2fa(&self, role: &str, required: bool) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO role_policies (role, require_2fa, updated_at) VALUES (?, ?, ?)
+             ON CONFLICT(role) DO UPDATE SET require_2fa = excluded.require_2fa, updated_at = excluded.updated_at",
+            params![role, required, now],
+        )?;
+        Ok(())
+    }
+
+    // SMTP settings methods
+    pub fn get_smtp_settings(&self) -> AppResult<Option<SmtpSettings>> {
+        Ok(self.conn.query_row(
+            "SELECT transport, host, port, tls_mode, username, password, from_address, drop_dir, organization_name, logo_url
+             FROM smtp_settings WHERE id = 1",
+            [],
+            |row| {
+                Ok(SmtpSettings {
+                    transport: row.get(0)?,
+                    host: row.get(1)?,
+                    port: row.get(2)?,
+                    tls_mode: row.get(3)?,
+                    username: row.get(4)?,
+                    password: row.get(5)?,
+                    from_address: row.get(6)?,
+                    drop_dir: row.get(7)?,
+                    organization_name: row.get(8)?,
+                    logo_url: row.get(9)?,
+                })
+            }
+        ).optional()?)
+    }
+
+    pub fn save_smtp_settings(&self, settings: &SmtpSettings) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO smtp_settings (id, transport, host, port, tls_mode, username, password, from_address, drop_dir,
+                 organization_name, logo_url, updated_at)
+             VALUES (1, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET transport = excluded.transport, host = excluded.host, port = excluded.port,
+                 tls_mode = excluded.tls_mode, username = excluded.username, password = excluded.password,
+                 from_address = excluded.from_address, drop_dir = excluded.drop_dir,
+                 organization_name = excluded.organization_name, logo_url = excluded.logo_url, updated_at = excluded.updated_at",
+            params![settings.transport, settings.host, settings.port, settings.tls_mode, settings.username, settings.password,
+                settings.from_address, settings.drop_dir, settings.organization_name, settings.logo_url, now],
+        )?;
+        Ok(())
+    }
+
+    // Email outbox methods
+    // `expires_at` is set for emails carrying a secret; they are never sent after it and
+    // their body isn't kept once they are sent or fail
+    pub fn enqueue_email(&self, to_address: &str, subject: &str, body: &str, html_body: Option<&str>, expires_at: Option<&str>) -> AppResult<String> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().to_rfc3339();
+        self.conn.execute(
+            "INSERT INTO email_outbox (id, to_address, subject, body, html_body, status, attempts, next_attempt_at, created_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, 0, ?, ?, ?)",
+            params![id, to_address, subject, body, html_body, EMAIL_STATUS_PENDING, now, now, expires_at],
+        )?;
+        Ok(id)
+    }
+
+    // Drop undelivered emails whose secret has expired. Returns the number removed.
+    pub fn purge_expired_emails(&self, now: &str) -> AppResult<usize> {
+        Ok(self.conn.execute(
+            "DELETE FROM email_outbox WHERE expires_at <= ? AND status <> ?",
+            params![now, EMAIL_STATUS_SENT],
+        )?)
+    }
+
+    pub fn get_due_emails(&self, now: &str, limit: i64) -> AppResult<Vec<QueuedEmail>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_address, subject, body, html_body, attempts FROM email_outbox
+             WHERE status = ?1 AND next_attempt_at <= ?2 AND (expires_at IS NULL OR expires_at > ?2)
+             ORDER BY next_attempt_at LIMIT ?3"
+        )?;
         
-        Ok(token_data)
+        let email_iter = stmt.query_map(params![EMAIL_STATUS_PENDING, now, limit], |row| {
+            Ok(QueuedEmail {
+                id: row.get(0)?,
+                to_address: row.get(1)?,
+                subject: row.get(2)?,
+                body: row.get(3)?,
+                html_body: row.get(4)?,
+                attempts: row.get(5)?,
+            })
+        })?;
+        
+        let mut emails = Vec::new();
+        for email in email_iter {
+            emails.push(email?);
+        }
+        
+        Ok(emails)
     }
 
-    pub fn mark_token_as_used(&self, token: &str) -> Result<()> {
+    // The bodies are dropped once delivered, they may hold a reset token
+    pub fn mark_email_sent(&self, id: &str) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
         self.conn.execute(
-            "UPDATE password_reset_tokens SET used = 1 WHERE token = ?",
-            params![token],
+            "UPDATE email_outbox SET status = ?, body = '', html_body = NULL, attempts = attempts + 1, last_error = NULL,
+                 next_attempt_at = NULL, sent_at = ?
+             WHERE id = ?",
+            params![EMAIL_STATUS_SENT, now, id],
         )?;
         Ok(())
     }
 
-    pub fn cleanup_expired_tokens(&self) -> Result<()> {
+    // Schedule another attempt, or mark the email failed when `next_attempt_at` is None.
+    // A failed email with a secret in it loses its body, it can't be retried anyway.
+    pub fn record_email_failure(&self, id: &str, attempts: i32, error: &str, next_attempt_at: Option<&str>) -> AppResult<()> {
+        let status = if next_attempt_at.is_some() { EMAIL_STATUS_PENDING } else { EMAIL_STATUS_FAILED };
+        self.conn.execute(
+            "UPDATE email_outbox SET status = ?1, attempts = ?2, last_error = ?3, next_attempt_at = ?4,
+                 body = CASE WHEN ?1 = ?6 AND expires_at IS NOT NULL THEN '' ELSE body END,
+                 html_body = CASE WHEN ?1 = ?6 AND expires_at IS NOT NULL THEN NULL ELSE html_body END
+             WHERE id = ?5",
+            params![status, attempts, error, next_attempt_at, id, EMAIL_STATUS_FAILED],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_outbox_emails(&self, status: Option<&str>) -> AppResult<Vec<OutboxEmail>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, to_address, subject, status, attempts, last_error, next_attempt_at, created_at, sent_at
+             FROM email_outbox
+             WHERE ?1 IS NULL OR status = ?1
+             ORDER BY created_at DESC"
+        )?;
+        
+        let email_iter = stmt.query_map(params![status], |row| {
+            Ok(OutboxEmail {
+                id: row.get(0)?,
+                to_address: row.get(1)?,
+                subject: row.get(2)?,
+                status: row.get(3)?,
+                attempts: row.get(4)?,
+                last_error: row.get(5)?,
+                next_attempt_at: row.get(6)?,
+                created_at: row.get(7)?,
+                sent_at: row.get(8)?,
+            })
+        })?;
+        
+        let mut emails = Vec::new();
+        for email in email_iter {
+            emails.push(email?);
+        }
+        
+        Ok(emails)
+    }
+
+    // Give a failed email a fresh set of attempts. Returns false if there was no failed email with
+    // that id, or it carried a secret (e.g. a password reset, which has to be requested again).
+    pub fn retry_outbox_email(&self, id: &str) -> AppResult<bool> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let updated = self.conn.execute(
+            "UPDATE email_outbox SET status = ?, attempts = 0, next_attempt_at = ? WHERE id = ? AND status = ? AND expires_at IS NULL",
+            params![EMAIL_STATUS_PENDING, now, id, EMAIL_STATUS_FAILED],
+        )?;
+        Ok(updated > 0)
+    }
+
+    // Remove an email that hasn't been delivered. Returns false if there was none with that id.
+    pub fn discard_outbox_email(&self, id: &str) -> AppResult<bool> {
+        let deleted = self.conn.execute(
+            "DELETE FROM email_outbox WHERE id = ? AND status <> ?",
+            params![id, EMAIL_STATUS_SENT],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    // Low-stock digest methods
+    pub fn get_stock_digest_subscriptions(&self) -> AppResult<Vec<StockDigestSubscription>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT s.user_id, u.username, u.email, s.include_moderate, s.created_at
+             FROM stock_digest_subscriptions s
+             JOIN users u ON u.id = s.user_id
+             ORDER BY u.username"
+        )?;
+        
+        let subscription_iter = stmt.query_map([], |row| {
+            Ok(StockDigestSubscription {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                email: row.get(2)?,
+                include_moderate: row.get(3)?,
+                created_at: row.get(4)?,
+            })
+        })?;
+        
+        let mut subscriptions = Vec::new();
+        for subscription in subscription_iter {
+            subscriptions.push(subscription?);
+        }
+        
+        Ok(subscriptions)
+    }
+
+    pub fn set_stock_digest_subscription(&self, user_id: &str, include_moderate: bool) -> AppResult<()> {
         let now = chrono::Utc::now().to_rfc3339();
         self.conn.execute(
-            "DELETE FROM password_reset_tokens WHERE expires_at < ? OR used = 1",
-            params![now],
+            "INSERT INTO stock_digest_subscriptions (user_id, include_moderate, created_at) VALUES (?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET include_moderate = excluded.include_moderate",
+            params![user_id, include_moderate, now],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_stock_digest_subscription(&self, user_id: &str) -> AppResult<()> {
+        self.conn.execute("DELETE FROM stock_digest_subscriptions WHERE user_id = ?", params![user_id])?;
+        self.conn.execute("DELETE FROM stock_digest_reports WHERE user_id = ?", params![user_id])?;
+        Ok(())
+    }
+
+    // Supplies at "Low" (and "Moderate" if asked for) that this user hasn't been told about
+    // at their current status. The returned supplies carry the freshly calculated status.
+    pub fn get_unreported_stock_items(&self, user_id: &str, include_moderate: bool) -> AppResult<Vec<Supply>> {
+        let mut reported: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+        {
+            let mut stmt = self.conn.prepare("SELECT supply_id, status FROM stock_digest_reports WHERE user_id = ?")?;
+            let rows = stmt.query_map(params![user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            for row in rows {
+                let (supply_id, status) = row?;
+                reported.insert(supply_id, status);
+            }
+        }
+        
+        let mut items = Vec::new();
+        for mut supply in self.get_supplies()? {
+            supply.status = self.calculate_stock_status(supply.quantity, supply.min_quantity);
+            let wanted = supply.status == STOCK_STATUS_LOW
+                || (include_moderate && supply.status == STOCK_STATUS_MODERATE);
+            if wanted && reported.get(&supply.id) != Some(&supply.status) {
+                items.push(supply);
+            }
+        }
+        
+        Ok(items)
+    }
+
+    pub fn record_stock_reports(&self, user_id: &str, supplies: &[Supply]) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        for supply in supplies {
+            self.conn.execute(
+                "INSERT INTO stock_digest_reports (user_id, supply_id, status, reported_at) VALUES (?, ?, ?, ?)
+                 ON CONFLICT(user_id, supply_id) DO UPDATE SET status = excluded.status, reported_at = excluded.reported_at",
+                params![user_id, supply.id, supply.status, now],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Drop reports for supplies that are gone or whose status has changed since
+    pub fn clear_changed_stock_reports(&self) -> AppResult<()> {
+        self.conn.execute(
+            "DELETE FROM stock_digest_reports WHERE supply_id NOT IN (SELECT id FROM supplies)",
+            [],
+        )?;
+        for supply in self.get_supplies()? {
+            let status = self.calculate_stock_status(supply.quantity, supply.min_quantity);
+            self.conn.execute(
+                "DELETE FROM stock_digest_reports WHERE supply_id = ? AND status <> ?",
+                params![supply.id, status],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_job_last_run(&self, job: &str) -> AppResult<Option<String>> {
+        Ok(self.conn.query_row(
+            "SELECT last_run_at FROM job_runs WHERE job = ?",
+            params![job],
+            |row| row.get(0)
+        ).optional()?)
+    }
+
+    pub fn set_job_last_run(&self, job: &str, last_run_at: &str) -> AppResult<()> {
+        self.conn.execute(
+            "INSERT INTO job_runs (job, last_run_at) VALUES (?, ?)
+             ON CONFLICT(job) DO UPDATE SET last_run_at = excluded.last_run_at",
+            params![job, last_run_at],
         )?;
         Ok(())
     }
 
     // Recalculate stock status for all supplies based on quantity vs min_quantity
-    pub fn recalculate_all_stock_status(&self) -> Result<()> {
+    pub fn recalculate_all_stock_status(&self) -> AppResult<()> {
         let now = chrono::Utc::now().to_rfc3339();
         
         // Get all supplies
@@ -1242,9 +2494,10 @@ impl Database {
         for supply in &supplies {
             let new_status = self.calculate_stock_status(supply.quantity, supply.min_quantity);
             
-            // Update the status in the database
+            // Update the status in the database. It's an edit like any other, so open edit
+            // forms are told; rows whose status stays the same are left alone.
             self.conn.execute(
-                "UPDATE supplies SET status = ?, updated_at = ? WHERE id = ?",
+                "UPDATE supplies SET status = ?1, version = version + 1, updated_at = ?2 WHERE id = ?3 AND status <> ?1",
                 params![new_status, now, supply.id],
             )?;
         }
@@ -1257,15 +2510,21 @@ impl Database {
         let moderate_threshold = (min_quantity as f64 * 1.5) as i32;
         
         if quantity <= min_quantity {
-            "Low".to_string()
+            STOCK_STATUS_LOW.to_string()
         } else if quantity <= moderate_threshold {
-            "Moderate".to_string()
+            STOCK_STATUS_MODERATE.to_string()
         } else {
-            "High".to_string()
+            STOCK_STATUS_HIGH.to_string()
         }
     }
 }
 
+// Someone else changed the supply first; the current row lets the caller merge
+fn supply_conflict(current: Supply) -> AppError {
+    AppError::conflict("This item was changed by someone else. Review the latest values and try again.")
+        .with_details(serde_json::json!({ "current": current }))
+}
+
 fn get_database_path() -> PathBuf {
     let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
     path.push(".ossms");
@@ -1273,10 +2532,252 @@ fn get_database_path() -> PathBuf {
     path
 }
 
-fn ensure_database_directory(db_path: &PathBuf) -> Result<()> {
+fn ensure_database_directory(db_path: &PathBuf) -> AppResult<()> {
     if let Some(parent) = db_path.parent() {
         fs::create_dir_all(parent)
-            .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+            .map_err(|e| AppError::internal(format!("Could not create database folder {}: {}", parent.display(), e)))?;
     }
     Ok(())
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(db: &Database, table: &str) -> i64 {
+        db.conn
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn database_with_supply() -> Database {
+        let db = Database::open(Connection::open_in_memory().unwrap()).unwrap();
+        db.conn
+            .execute(
+                "INSERT INTO users (id, username, password, firstname, lastname, email, role, permissions, created_at, updated_at)
+                 VALUES ('u1', 'clerk', 'x', 'Ann', 'Reyes', 'ann@example.org', 'user', '{}', '2024-01-01', '2024-01-01')",
+                [],
+            )
+            .unwrap();
+        let supply = Supply {
+            id: "s1".to_string(),
+            name: "Paper".to_string(),
+            description: None,
+            category: "Office".to_string(),
+            subcategory: None,
+            variation: None,
+            brand: None,
+            quantity: 10,
+            unit: "ream".to_string(),
+            min_quantity: 2,
+            status: "normal".to_string(),
+            location: None,
+            supplier: None,
+            supplier_name: None,
+            supplier_contact: None,
+            supplier_notes: None,
+            cost: None,
+            pieces_per_bulk: None,
+            created_at: "2024-01-01".to_string(),
+            updated_at: "2024-01-01".to_string(),
+            version: 1,
+        };
+        db.create_supply(&supply, "u1").unwrap();
+        db
+    }
+
+    #[test]
+    fn failed_history_insert_rolls_back_the_stock_change() {
+        let db = database_with_supply();
+        assert_eq!(count(&db, "supply_histories"), 1);
+
+        db.conn
+            .execute_batch(
+                "CREATE TRIGGER fail_history BEFORE INSERT ON supply_histories
+                 BEGIN SELECT RAISE(ABORT, 'history insert failed'); END;",
+            )
+            .unwrap();
+
+        let request: UpdateSupplyRequest = serde_json::from_value(serde_json::json!({ "id": "s1", "quantity": 4, "version": 1 })).unwrap();
+        assert!(db.update_supply("s1", &request, "u1").is_err());
+        assert!(db.delete_supply("s1", "u1").is_err());
+
+        // Neither the stock change nor the deletion went through without its ledger entry
+        assert_eq!(db.get_supply_quantity("s1").unwrap(), 10);
+        assert_eq!(count(&db, "supply_histories"), 1);
+        assert!(db.conn.is_autocommit());
+
+        db.conn.execute_batch("DROP TRIGGER fail_history").unwrap();
+        db.update_supply("s1", &request, "u1").unwrap();
+        assert_eq!(db.get_supply_quantity("s1").unwrap(), 4);
+        assert_eq!(count(&db, "supply_histories"), 2);
+    }
+
+    #[test]
+    fn stale_update_is_refused_with_the_current_row() {
+        let db = database_with_supply();
+        let update = |quantity: i32, version: i64| -> UpdateSupplyRequest {
+            serde_json::from_value(serde_json::json!({ "id": "s1", "quantity": quantity, "version": version })).unwrap()
+        };
+
+        // Both windows loaded version 1; the first edit wins
+        db.update_supply("s1", &update(15, 1), "u1").unwrap();
+        let error = db.update_supply("s1", &update(7, 1), "u1").unwrap_err();
+
+        assert_eq!(error.code, crate::error::ErrorCode::Conflict);
+        let current = &error.details.unwrap()["current"];
+        assert_eq!(current["quantity"], 15);
+        assert_eq!(current["version"], 2);
+        assert_eq!(db.get_supply_quantity("s1").unwrap(), 15);
+        assert_eq!(count(&db, "supply_histories"), 2);
+
+        // Retrying against the current version goes through
+        db.update_supply("s1", &update(7, 2), "u1").unwrap();
+        assert_eq!(db.get_supply_by_id("s1").unwrap().version, 3);
+
+        // Recalculating statuses counts as an edit, but only where one changes
+        db.recalculate_all_stock_status().unwrap();
+        let version = db.get_supply_by_id("s1").unwrap().version;
+        db.recalculate_all_stock_status().unwrap();
+        assert_eq!(db.get_supply_by_id("s1").unwrap().version, version);
+        db.conn.execute("UPDATE supplies SET min_quantity = 20 WHERE id = 's1'", []).unwrap();
+        db.recalculate_all_stock_status().unwrap();
+        assert_eq!(db.get_supply_by_id("s1").unwrap().version, version + 1);
+        let error = db.update_supply("s1", &update(9, version), "u1").unwrap_err();
+        assert_eq!(error.code, crate::error::ErrorCode::Conflict);
+    }
+
+    #[test]
+    fn stock_out_that_would_go_negative_is_refused() {
+        let db = database_with_supply();
+        let movement = |quantity: i32, reason_code: &str| StockMovementRequest {
+            supply_id: "s1".to_string(),
+            quantity,
+            reason_code: reason_code.to_string(),
+            reference_number: "RIS-0042".to_string(),
+            department: Some("Registrar".to_string()),
+            notes: None,
+        };
+
+        let supply = db.apply_stock_movement(StockMovement::Out, &movement(8, "issued"), "u1").unwrap();
+        assert_eq!((supply.quantity, supply.status.as_str(), supply.version), (2, STOCK_STATUS_LOW, 2));
+
+        let error = db.apply_stock_movement(StockMovement::Out, &movement(3, "issued"), "u1").err().unwrap();
+        assert_eq!(error.code, crate::error::ErrorCode::Conflict);
+        assert_eq!(error.details.unwrap()["available"], 2);
+        assert_eq!(db.get_supply_quantity("s1").unwrap(), 2);
+
+        let error = db.apply_stock_movement(StockMovement::Adjust, &movement(-3, "lost"), "u1").err().unwrap();
+        assert_eq!(error.code, crate::error::ErrorCode::Conflict);
+
+        let error = db.apply_stock_movement(StockMovement::In, &movement(5, "issued"), "u1").err().unwrap();
+        assert_eq!(error.code, crate::error::ErrorCode::Validation);
+        assert_eq!(count(&db, "supply_histories"), 2);
+
+        db.apply_stock_movement(StockMovement::In, &movement(5, "purchase"), "u1").unwrap();
+        let history = db.get_supply_histories().unwrap();
+        let latest = history.iter().find(|h| h.action == "Stock In").unwrap();
+        assert_eq!((latest.quantity, latest.previous_quantity, latest.new_quantity), (5, 2, 7));
+        assert_eq!(latest.reason_code.as_deref(), Some("purchase"));
+        assert_eq!(latest.reference_number.as_deref(), Some("RIS-0042"));
+        assert_eq!(latest.department.as_deref(), Some("Registrar"));
+    }
+
+    #[test]
+    fn reset_emails_are_not_kept_past_their_token() {
+        let db = Database::open(Connection::open_in_memory().unwrap()).unwrap();
+        let now = chrono::Utc::now();
+        let later = (now + chrono::Duration::hours(1)).to_rfc3339();
+        let earlier = (now - chrono::Duration::minutes(1)).to_rfc3339();
+
+        let failing = db.enqueue_email("ann@example.org", "Reset", "token abc", None, Some(&later)).unwrap();
+        db.enqueue_email("ann@example.org", "Reset", "token def", None, Some(&earlier)).unwrap();
+        db.enqueue_email("ann@example.org", "Welcome", "hello", None, None).unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+
+        // The expired one is never handed out and goes at the next purge
+        let due: Vec<String> = db.get_due_emails(&now, 10).unwrap().into_iter().map(|e| e.body).collect();
+        assert_eq!(due.len(), 2);
+        assert!(!due.contains(&"token def".to_string()));
+        assert_eq!(db.purge_expired_emails(&now).unwrap(), 1);
+
+        // Giving up on a reset email wipes the token and rules out a retry
+        db.record_email_failure(&failing, 5, "refused", None).unwrap();
+        let body: String = db.conn.query_row("SELECT body FROM email_outbox WHERE id = ?", params![failing], |row| row.get(0)).unwrap();
+        assert_eq!(body, "");
+        assert!(!db.retry_outbox_email(&failing).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let db = database_with_supply();
+        let code_hash = crate::totp::hash_recovery_code("abcde-fghjk");
+        db.replace_recovery_codes("u1", std::slice::from_ref(&code_hash)).unwrap();
+
+        assert!(!db.consume_recovery_code("u1", &crate::totp::hash_recovery_code("abcde-fghjm")).unwrap());
+        assert!(db.consume_recovery_code("u1", &crate::totp::hash_recovery_code("ABCDE-FGHJK")).unwrap());
+        assert!(!db.consume_recovery_code("u1", &code_hash).unwrap());
+
+        // New codes replace the old ones, used or not
+        db.replace_recovery_codes("u1", &[crate::totp::hash_recovery_code("zzzzz-zzzzz")]).unwrap();
+        assert_eq!(count(&db, "recovery_codes"), 1);
+    }
+
+    #[test]
+    fn password_reset_uses_up_every_token() {
+        let db = database_with_supply();
+        let expires_at = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        db.create_password_reset_token("u1", "first", &expires_at).unwrap();
+        db.create_password_reset_token("u1", "second", &expires_at).unwrap();
+
+        db.complete_password_reset("u1", "new-hash").unwrap();
+
+        assert!(db.get_password_reset_tokens_for_user("u1").unwrap().iter().all(|token| token.used));
+        assert_eq!(db.get_user_by_id("u1").unwrap().unwrap().password, "new-hash");
+    }
+
+    #[test]
+    fn stock_movements_are_bounded() {
+        let db = database_with_supply();
+        let movement = |quantity: i32| StockMovementRequest {
+            supply_id: "s1".to_string(),
+            quantity,
+            reason_code: "other".to_string(),
+            reference_number: "RIS-0043".to_string(),
+            department: None,
+            notes: None,
+        };
+
+        for (kind, quantity) in [
+            (StockMovement::In, 0),
+            (StockMovement::Out, -5),
+            (StockMovement::In, MAX_STOCK_MOVEMENT + 1),
+            (StockMovement::Adjust, i32::MIN),
+        ] {
+            let error = db.apply_stock_movement(kind, &movement(quantity), "u1").err().unwrap();
+            assert_eq!(error.code, crate::error::ErrorCode::Validation);
+        }
+
+        // Repeated movements can't take the quantity past what the column holds
+        db.conn.execute("UPDATE supplies SET quantity = ?1 WHERE id = 's1'", params![i32::MAX - 10]).unwrap();
+        let error = db.apply_stock_movement(StockMovement::In, &movement(MAX_STOCK_MOVEMENT), "u1").err().unwrap();
+        assert_eq!(error.code, crate::error::ErrorCode::Validation);
+        assert_eq!(db.get_supply_quantity("s1").unwrap(), i32::MAX - 10);
+        assert_eq!(count(&db, "supply_histories"), 1);
+    }
+
+    #[test]
+    fn sessions_are_stored_hashed() {
+        let db = database_with_supply();
+        let expires_at = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        db.create_session("u1", "secret-token", SESSION_SCOPE_FULL, &expires_at).unwrap();
+
+        let stored: String = db.conn.query_row("SELECT token FROM sessions", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, hash_token("secret-token"));
+        // Someone holding the stored value can't use it as a token
+        assert!(db.get_session_user(&stored).unwrap().is_none());
+        assert_eq!(db.get_session_user("secret-token").unwrap().unwrap().id, "u1");
+
+        db.delete_session("secret-token").unwrap();
+        assert_eq!(count(&db, "sessions"), 0);
+    }
+}
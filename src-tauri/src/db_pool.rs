@@ -0,0 +1,176 @@
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::config::env_or;
+use crate::database::Database;
+use crate::error::{AppError, AppResult};
+
+// Connections to the app database. SQLite in WAL mode lets any number of readers work
+// alongside one writer, so reads are spread over a few read-only connections and all
+// writes go through a single writer connection, one at a time.
+//
+// Database calls block, so `read` and `write` hand them to the runtime's blocking thread
+// pool and await the result; the async worker threads keep serving other tasks meanwhile.
+// Cloning the pool is cheap, all clones share the same connections.
+//
+// Defaults can be overridden with environment variables:
+//   OSSMS_DB_READERS  number of read-only connections
+#[derive(Clone)]
+pub struct DbPool {
+    connections: Arc<Connections>,
+}
+
+struct Connections {
+    writer: Mutex<Database>,
+    readers: Mutex<Vec<Database>>,
+    reader_returned: Condvar,
+}
+
+const DEFAULT_READERS: usize = 4;
+
+impl DbPool {
+    pub fn open() -> AppResult<Self> {
+        // The writer creates and migrates the database, so it has to come first
+        let writer = Database::new()?;
+        let size = env_or("OSSMS_DB_READERS", DEFAULT_READERS).max(1);
+        let readers = (0..size)
+            .map(|_| Database::new_reader())
+            .collect::<AppResult<Vec<_>>>()?;
+        Ok(DbPool::with_connections(writer, readers))
+    }
+
+    fn with_connections(writer: Database, readers: Vec<Database>) -> Self {
+        DbPool {
+            connections: Arc::new(Connections {
+                writer: Mutex::new(writer),
+                readers: Mutex::new(readers),
+                reader_returned: Condvar::new(),
+            }),
+        }
+    }
+
+    // Run `f` on a read-only connection. Any attempt to write fails.
+    pub async fn read<T, F>(&self, f: F) -> AppResult<T>
+    where
+        F: FnOnce(&Database) -> AppResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connections = Arc::clone(&self.connections);
+        blocking(move || {
+            let reader = connections.take_reader()?;
+            f(&reader)
+        })
+        .await
+    }
+
+    // Run `f` on the writer connection. Writers wait for each other, readers don't wait for them.
+    pub async fn write<T, F>(&self, f: F) -> AppResult<T>
+    where
+        F: FnOnce(&Database) -> AppResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connections = Arc::clone(&self.connections);
+        blocking(move || {
+            let writer = connections.writer.lock().map_err(|_| AppError::internal("Database lock failed"))?;
+            f(&writer)
+        })
+        .await
+    }
+}
+
+impl Connections {
+    // Wait for a free reader; it goes back to the pool when the guard is dropped
+    fn take_reader(&self) -> AppResult<Reader<'_>> {
+        let mut readers = self.readers.lock().map_err(|_| AppError::internal("Database lock failed"))?;
+        loop {
+            if let Some(db) = readers.pop() {
+                return Ok(Reader { pool: self, db: Some(db) });
+            }
+            readers = self
+                .reader_returned
+                .wait(readers)
+                .map_err(|_| AppError::internal("Database lock failed"))?;
+        }
+    }
+}
+
+struct Reader<'a> {
+    pool: &'a Connections,
+    db: Option<Database>,
+}
+
+impl Deref for Reader<'_> {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db.as_ref().expect("reader is only taken on drop")
+    }
+}
+
+impl Drop for Reader<'_> {
+    fn drop(&mut self) {
+        if let (Some(db), Ok(mut readers)) = (self.db.take(), self.pool.readers.lock()) {
+            readers.push(db);
+            self.pool.reader_returned.notify_one();
+        }
+    }
+}
+
+// Run `f` on the blocking thread pool and wait for it without tying up the caller's thread
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> AppResult<T> + Send + 'static) -> AppResult<T> {
+    tauri::async_runtime::spawn_blocking(f)
+        .await
+        .map_err(|_| AppError::internal("Database task failed"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reads_do_not_wait_for_a_held_writer() {
+        let path = std::env::temp_dir().join(format!("ossms-pool-{}.db", uuid::Uuid::new_v4()));
+        let writer = Database::open_path(&path).unwrap();
+        let readers = (0..2).map(|_| Database::reader_at(&path).unwrap()).collect();
+        let pool = DbPool::with_connections(writer, readers);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        let waited = runtime.block_on(async {
+            let (held, writer_holds) = tokio::sync::oneshot::channel();
+            let write = tokio::spawn({
+                let pool = pool.clone();
+                async move {
+                    pool.write(move |_| {
+                        let _ = held.send(());
+                        std::thread::sleep(Duration::from_millis(800));
+                        Ok(())
+                    })
+                    .await
+                }
+            });
+            writer_holds.await.unwrap();
+
+            let started = Instant::now();
+            let reads: Vec<_> = (0..4)
+                .map(|_| {
+                    let pool = pool.clone();
+                    tokio::spawn(async move { pool.read(|db| db.count_supplies()).await })
+                })
+                .collect();
+            for read in reads {
+                assert_eq!(read.await.unwrap().unwrap(), 0);
+            }
+            let waited = started.elapsed();
+
+            write.await.unwrap().unwrap();
+            waited
+        });
+
+        drop(pool);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        assert!(waited < Duration::from_millis(400), "reads took {:?}", waited);
+    }
+}
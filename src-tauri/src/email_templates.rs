@@ -0,0 +1,220 @@
+use std::path::PathBuf;
+
+use crate::database::SmtpSettings;
+use crate::mailer::OutgoingEmail;
+
+// Every email the app sends. Each has a subject, a plain-text body and an HTML body;
+// the HTML body is wrapped in the shared layout.
+//
+// Built-in templates live in src-tauri/templates/email. Any of them can be overridden
+// by a file with the same name in the template folder: OSSMS_EMAIL_TEMPLATE_DIR, or
+// an email-templates folder next to the database.
+//
+// Placeholders are written {{name}}. Values are HTML-escaped in HTML templates;
+// {{{name}}} inserts a value as-is and is only used for markup built here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailTemplate {
+    PasswordReset,
+    Welcome,
+    Alert,
+}
+
+struct TemplateSource {
+    subject: &'static str,
+    text: &'static str,
+    html: &'static str,
+}
+
+const LAYOUT_HTML: &str = include_str!("../templates/email/layout.html");
+
+impl EmailTemplate {
+    fn name(&self) -> &'static str {
+        match self {
+            EmailTemplate::PasswordReset => "password_reset",
+            EmailTemplate::Welcome => "welcome",
+            EmailTemplate::Alert => "alert",
+        }
+    }
+
+    fn built_in(&self) -> TemplateSource {
+        match self {
+            EmailTemplate::PasswordReset => TemplateSource {
+                subject: include_str!("../templates/email/password_reset.subject.txt"),
+                text: include_str!("../templates/email/password_reset.txt"),
+                html: include_str!("../templates/email/password_reset.html"),
+            },
+            EmailTemplate::Welcome => TemplateSource {
+                subject: include_str!("../templates/email/welcome.subject.txt"),
+                text: include_str!("../templates/email/welcome.txt"),
+                html: include_str!("../templates/email/welcome.html"),
+            },
+            EmailTemplate::Alert => TemplateSource {
+                subject: include_str!("../templates/email/alert.subject.txt"),
+                text: include_str!("../templates/email/alert.txt"),
+                html: include_str!("../templates/email/alert.html"),
+            },
+        }
+    }
+}
+
+// Organization details shown in every email
+pub struct Branding {
+    pub organization_name: String,
+    pub logo_url: String,
+}
+
+impl Branding {
+    pub fn from_settings(settings: &SmtpSettings) -> Self {
+        Branding {
+            organization_name: settings.organization_name.clone(),
+            logo_url: settings.logo_url.clone(),
+        }
+    }
+}
+
+pub fn template_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("OSSMS_EMAIL_TEMPLATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push(".ossms");
+    path.push("email-templates");
+    path
+}
+
+// The override on disk if there is one, otherwise the built-in template
+fn load(file_name: &str, built_in: &str) -> String {
+    std::fs::read_to_string(template_dir().join(file_name)).unwrap_or_else(|_| built_in.to_string())
+}
+
+pub fn render(template: EmailTemplate, branding: &Branding, to: &str, vars: &[(&str, &str)]) -> OutgoingEmail {
+    let name = template.name();
+    let source = template.built_in();
+
+    let logo_html = if branding.logo_url.trim().is_empty() {
+        String::new()
+    } else {
+        format!(
+            "<img src=\"{}\" alt=\"{}\" style=\"max-height:48px;margin-bottom:8px;\">",
+            escape_html(branding.logo_url.trim()),
+            escape_html(&branding.organization_name)
+        )
+    };
+
+    let mut all_vars: Vec<(&str, &str)> = vec![
+        ("organization_name", &branding.organization_name),
+        ("logo_url", &branding.logo_url),
+    ];
+    all_vars.extend_from_slice(vars);
+
+    let subject = fill(&load(&format!("{}.subject.txt", name), source.subject), &all_vars, false);
+    let subject = subject.trim().to_string();
+    let text = fill(&load(&format!("{}.txt", name), source.text), &all_vars, false);
+    let content = fill(&load(&format!("{}.html", name), source.html), &all_vars, true);
+
+    all_vars.push(("subject", &subject));
+    all_vars.push(("logo_html", &logo_html));
+    all_vars.push(("content", &content));
+    let html = fill(&load("layout.html", LAYOUT_HTML), &all_vars, true);
+
+    OutgoingEmail {
+        to: to.to_string(),
+        subject,
+        body: text,
+        html_body: Some(html),
+    }
+}
+
+// Replace {{name}} and {{{name}}} placeholders. Unknown names render as nothing.
+fn fill(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let (raw, open, close) = if placeholder.starts_with("{{{") { (true, 3, "}}}") } else { (false, 2, "}}") };
+
+        match placeholder[open..].find(close) {
+            Some(end) => {
+                let key = placeholder[open..open + end].trim();
+                if let Some((_, value)) = vars.iter().rev().find(|(name, _)| *name == key) {
+                    if html && !raw {
+                        out.push_str(&escape_html(value));
+                    } else {
+                        out.push_str(value);
+                    }
+                }
+                rest = &placeholder[open + end + close.len()..];
+            }
+            None => {
+                out.push_str(placeholder);
+                rest = "";
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_escapes_values_in_html_unless_raw() {
+        let vars = [("name", "<b>Ann</b> & 'co'"), ("link", "<a href=\"x\">x</a>")];
+
+        assert_eq!(fill("Hi {{name}}", &vars, true), "Hi &lt;b&gt;Ann&lt;/b&gt; &amp; &#39;co&#39;");
+        assert_eq!(fill("Hi {{ name }}", &vars, false), "Hi <b>Ann</b> & 'co'");
+        assert_eq!(fill("{{{link}}}", &vars, true), "<a href=\"x\">x</a>");
+    }
+
+    #[test]
+    fn fill_leaves_unknown_and_unterminated_placeholders_harmless() {
+        let vars = [("name", "Ann"), ("name", "Ben")];
+
+        // Later values win, unknown names render as nothing
+        assert_eq!(fill("{{name}}{{missing}}!", &vars, true), "Ben!");
+        assert_eq!(fill("Hi {{name", &vars, true), "Hi {{name");
+        assert_eq!(fill("Hi {{{name}}", &vars, true), "Hi {{{name}}");
+        assert_eq!(fill("{} }} {", &vars, true), "{} }} {");
+    }
+
+    #[test]
+    fn templates_can_be_overridden_on_disk() {
+        let dir = std::env::temp_dir().join(format!("ossms-templates-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("welcome.subject.txt"), "Hello from {{organization_name}}\n").unwrap();
+        std::env::set_var("OSSMS_EMAIL_TEMPLATE_DIR", &dir);
+
+        let branding = Branding { organization_name: "R&D Office".to_string(), logo_url: String::new() };
+        let email = render(EmailTemplate::Welcome, &branding, "ann@example.org", &[]);
+
+        std::env::remove_var("OSSMS_EMAIL_TEMPLATE_DIR");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The override replaces the subject only; the other parts stay built in
+        assert_eq!(email.subject, "Hello from R&D Office");
+        let html = email.html_body.unwrap();
+        assert!(html.contains("Hello from R&amp;D Office"));
+        assert!(!html.contains("{{"));
+        assert_eq!(email.to, "ann@example.org");
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use rusqlite::ffi;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+
+use crate::mailer::MailError;
+use crate::migrations::MigrationError;
+
+// What kind of failure an error is. The frontend branches on these, so the
+// serialized names are part of the API and must not change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // The record the request refers to doesn't exist
+    NotFound,
+    // The request clashes with existing data, e.g. a duplicate username
+    Conflict,
+    // The input was rejected, e.g. a weak password or an invalid email address
+    Validation,
+    // No valid session, or the session lacks the required permission
+    Forbidden,
+    // Anything else; the message goes to the log, the frontend gets INTERNAL_ERROR_MESSAGE
+    Internal,
+}
+
+// The error type of Database and every command, sent to the frontend as
+// { "code": "NOT_FOUND", "message": "User not found" }
+#[derive(Debug)]
+pub struct AppError {
+    pub code: ErrorCode,
+    pub message: String,
+    // Extra data for the frontend, e.g. the current row on an edit conflict
+    pub details: Option<serde_json::Value>,
+}
+
+// Sent in place of the message of an internal error, which can hold SQL, file paths
+// or library output
+pub const INTERNAL_ERROR_MESSAGE: &str = "Something went wrong, please try again";
+
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        AppError { code, message: message.into(), details: None }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::Validation, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        AppError::new(ErrorCode::Internal, message)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let message = if self.code == ErrorCode::Internal {
+            eprintln!("Internal error: {}", self.message);
+            INTERNAL_ERROR_MESSAGE
+        } else {
+            &self.message
+        };
+
+        let mut error = serializer.serialize_struct("AppError", if self.details.is_some() { 3 } else { 2 })?;
+        error.serialize_field("code", &self.code)?;
+        error.serialize_field("message", message)?;
+        if let Some(details) = &self.details {
+            error.serialize_field("details", details)?;
+        }
+        error.end()
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        match &e {
+            rusqlite::Error::QueryReturnedNoRows => AppError::not_found("Record not found"),
+            rusqlite::Error::SqliteFailure(failure, _) if failure.code == rusqlite::ErrorCode::ConstraintViolation => {
+                match failure.extended_code {
+                    // Constraint names give the schema away, so they stay out of the message
+                    ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => AppError::conflict("Record already exists"),
+                    ffi::SQLITE_CONSTRAINT_FOREIGNKEY => AppError::conflict("Record is referenced elsewhere"),
+                    _ => AppError::validation("Invalid data"),
+                }
+            }
+            _ => AppError::internal(format!("Database error: {}", e)),
+        }
+    }
+}
+
+impl From<bcrypt::BcryptError> for AppError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        AppError::internal(format!("Password hashing error: {}", e))
+    }
+}
+
+impl From<MailError> for AppError {
+    fn from(e: MailError) -> Self {
+        match e {
+            MailError::InvalidAddress { .. } | MailError::NotConfigured | MailError::Config(_) => {
+                AppError::validation(e.to_string())
+            }
+            MailError::Message(_) | MailError::Delivery(_) => AppError::internal(e.to_string()),
+        }
+    }
+}
+
+impl From<MigrationError> for AppError {
+    fn from(e: MigrationError) -> Self {
+        AppError::internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_details_stay_out_of_the_payload() {
+        let error = AppError::from(rusqlite::Error::InvalidColumnName("secret_column".to_string()));
+        assert_eq!(error.code, ErrorCode::Internal);
+        assert!(error.message.contains("secret_column"));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({ "code": "INTERNAL", "message": INTERNAL_ERROR_MESSAGE })
+        );
+
+        let error = AppError::conflict("Edited elsewhere").with_details(serde_json::json!({ "version": 2 }));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({ "code": "CONFLICT", "message": "Edited elsewhere", "details": { "version": 2 } })
+        );
+    }
+}
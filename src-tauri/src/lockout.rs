@@ -0,0 +1,48 @@
+use chrono::Duration;
+
+use crate::config::env_or;
+
+// Login throttling policy. Defaults can be overridden with environment variables:
+//   OSSMS_LOGIN_MAX_ATTEMPTS      failed attempts allowed before the first lockout
+//   OSSMS_LOGIN_LOCKOUT_SECS      length of the first lockout
+//   OSSMS_LOGIN_LOCKOUT_MAX_SECS  upper bound for the doubling backoff
+pub struct LockoutPolicy {
+    pub max_failed_attempts: i32,
+    pub base_lockout_secs: i64,
+    pub max_lockout_secs: i64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failed_attempts: 5,
+            base_lockout_secs: 60,
+            max_lockout_secs: 60 * 60,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    pub fn from_env() -> Self {
+        let defaults = LockoutPolicy::default();
+        LockoutPolicy {
+            max_failed_attempts: env_or("OSSMS_LOGIN_MAX_ATTEMPTS", defaults.max_failed_attempts),
+            base_lockout_secs: env_or("OSSMS_LOGIN_LOCKOUT_SECS", defaults.base_lockout_secs),
+            max_lockout_secs: env_or("OSSMS_LOGIN_LOCKOUT_MAX_SECS", defaults.max_lockout_secs),
+        }
+    }
+
+    // Lockout to apply after `failed_attempts` consecutive failures, if any.
+    // Each failure past the limit doubles the lockout, up to the configured maximum.
+    pub fn lockout_for(&self, failed_attempts: i32) -> Option<Duration> {
+        if failed_attempts < self.max_failed_attempts {
+            return None;
+        }
+        let doublings = (failed_attempts - self.max_failed_attempts).min(30) as u32;
+        let secs = self
+            .base_lockout_secs
+            .saturating_mul(1i64 << doublings)
+            .min(self.max_lockout_secs);
+        Some(Duration::seconds(secs))
+    }
+}
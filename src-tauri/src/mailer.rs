@@ -0,0 +1,268 @@
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use lettre::message::{Mailbox, MultiPart};
+use lettre::{Address, Message};
+
+use crate::database::SmtpSettings;
+use crate::smtp::SmtpMailer;
+
+// An email ready to be handed to a mailer, see email_templates::render.
+// With an HTML body it is sent as multipart/alternative.
+#[derive(Debug, Clone)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub html_body: Option<String>,
+}
+
+// Why an email could not be sent
+#[derive(Debug)]
+pub enum MailError {
+    InvalidAddress { address: String, reason: String },
+    NotConfigured,
+    // The mail settings can't be used, e.g. an unknown TLS mode
+    Config(String),
+    // The message itself could not be put together
+    Message(String),
+    // The transport failed or refused to deliver
+    Delivery(String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::InvalidAddress { address, reason } => write!(f, "Invalid email address '{}': {}", address, reason),
+            MailError::NotConfigured => f.write_str("Email is not configured"),
+            MailError::Config(reason) => write!(f, "Invalid mail settings: {}", reason),
+            MailError::Message(reason) => write!(f, "Failed to create email: {}", reason),
+            MailError::Delivery(reason) => write!(f, "Failed to send email: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+// Trimmed, lower-cased address, so addresses compare case-insensitively
+pub fn normalize_address(address: &str) -> Result<String, MailError> {
+    let normalized = address.trim().to_lowercase();
+    normalized.parse::<Address>().map_err(|e| MailError::InvalidAddress {
+        address: address.trim().to_string(),
+        reason: e.to_string(),
+    })?;
+    Ok(normalized)
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox, MailError> {
+    address.trim().parse().map_err(|e: lettre::address::AddressError| MailError::InvalidAddress {
+        address: address.trim().to_string(),
+        reason: e.to_string(),
+    })
+}
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), MailError>> + Send + 'a>>;
+
+// Every outgoing email goes through a Mailer. Most emails are queued in the outbox
+// and sent by its worker rather than calling this directly.
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> SendFuture<'a>;
+}
+
+// Which Mailer the settings select
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailTransport {
+    Smtp,
+    // Write .eml files to a folder instead of sending them
+    FileDrop,
+    // Keep emails in memory, nothing is delivered
+    Memory,
+}
+
+impl MailTransport {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "smtp" => Ok(MailTransport::Smtp),
+            "file" => Ok(MailTransport::FileDrop),
+            "memory" => Ok(MailTransport::Memory),
+            other => Err(format!("Unknown mail transport '{}', expected smtp, file or memory", other)),
+        }
+    }
+}
+
+pub fn from_settings(settings: &SmtpSettings) -> Result<Arc<dyn Mailer>, MailError> {
+    let mailer: Arc<dyn Mailer> = match MailTransport::parse(&settings.transport).map_err(MailError::Config)? {
+        MailTransport::Smtp => Arc::new(SmtpMailer::new(settings.clone())),
+        MailTransport::FileDrop => Arc::new(FileDropMailer::new(drop_dir(settings), sender(settings))),
+        MailTransport::Memory => Arc::new(MemoryMailer::default()),
+    };
+    Ok(mailer)
+}
+
+// Folder for the file-drop mailer, next to the database unless configured
+pub fn drop_dir(settings: &SmtpSettings) -> PathBuf {
+    if !settings.drop_dir.trim().is_empty() {
+        return PathBuf::from(settings.drop_dir.trim());
+    }
+    let mut path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push(".ossms");
+    path.push("mail");
+    path
+}
+
+// The configured from address, named after the organization unless it already has a name
+pub fn sender(settings: &SmtpSettings) -> String {
+    match settings.from_address.parse::<Mailbox>() {
+        Ok(mailbox) if mailbox.name.is_none() && !settings.organization_name.trim().is_empty() => {
+            Mailbox::new(Some(settings.organization_name.trim().to_string()), mailbox.email).to_string()
+        }
+        _ => settings.from_address.clone(),
+    }
+}
+
+pub fn build_message(from_address: &str, email: &OutgoingEmail) -> Result<Message, MailError> {
+    let builder = Message::builder()
+        .from(parse_mailbox(from_address)?)
+        .to(parse_mailbox(&email.to)?)
+        .subject(email.subject.as_str());
+
+    match &email.html_body {
+        Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.body.clone(), html.clone())),
+        None => builder.body(email.body.clone()),
+    }
+    .map_err(|e| MailError::Message(e.to_string()))
+}
+
+// Writes each email to its own .eml file, for offices without an SMTP server
+pub struct FileDropMailer {
+    dir: PathBuf,
+    from_address: String,
+}
+
+impl FileDropMailer {
+    pub fn new(dir: PathBuf, from_address: String) -> Self {
+        FileDropMailer { dir, from_address }
+    }
+}
+
+impl Mailer for FileDropMailer {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> SendFuture<'a> {
+        Box::pin(async move {
+            let message = build_message(&self.from_address, email)?;
+
+            // Small local writes, not worth a blocking thread
+            std::fs::create_dir_all(&self.dir)
+                .map_err(|e| MailError::Delivery(format!("could not create mail folder {}: {}", self.dir.display(), e)))?;
+            let file_name = format!(
+                "{}-{}.eml",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+                uuid::Uuid::new_v4()
+            );
+            let path = self.dir.join(file_name);
+            std::fs::write(&path, message.formatted())
+                .map_err(|e| MailError::Delivery(format!("could not write {}: {}", path.display(), e)))
+        })
+    }
+}
+
+// Collects emails instead of sending them
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<OutgoingEmail>>,
+}
+
+impl MemoryMailer {
+    // Emails sent so far, for tests to inspect
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<OutgoingEmail> {
+        self.sent.lock().map(|sent| sent.clone()).unwrap_or_default()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> SendFuture<'a> {
+        let result = self
+            .sent
+            .lock()
+            .map(|mut sent| sent.push(email.clone()))
+            .map_err(|_| MailError::Delivery("mailer lock failed".to_string()));
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(html_body: Option<&str>) -> OutgoingEmail {
+        OutgoingEmail {
+            to: "ann@example.org".to_string(),
+            subject: "Password reset".to_string(),
+            body: "Use the link below".to_string(),
+            html_body: html_body.map(str::to_string),
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn addresses_are_normalized_and_checked() {
+        assert_eq!(normalize_address(" Ann@Example.ORG ").unwrap(), "ann@example.org");
+        assert!(matches!(normalize_address("ann at example.org"), Err(MailError::InvalidAddress { .. })));
+    }
+
+    #[test]
+    fn sender_is_named_after_the_organization() {
+        let settings = SmtpSettings::default();
+        assert_eq!(sender(&settings), "OSSMS <noreply@ossms.com>");
+
+        let named = SmtpSettings { from_address: "Stockroom <stock@example.org>".to_string(), ..SmtpSettings::default() };
+        assert_eq!(sender(&named), "Stockroom <stock@example.org>");
+    }
+
+    #[test]
+    fn html_emails_are_sent_as_alternatives() {
+        let plain = String::from_utf8(build_message("noreply@ossms.com", &email(None)).unwrap().formatted()).unwrap();
+        assert!(plain.contains("Subject: Password reset"));
+        assert!(!plain.contains("multipart/alternative"));
+
+        let html = String::from_utf8(build_message("noreply@ossms.com", &email(Some("<p>Use the link below</p>"))).unwrap().formatted()).unwrap();
+        assert!(html.contains("multipart/alternative"));
+        assert!(html.contains("<p>Use the link below</p>"));
+
+        let bad = OutgoingEmail { to: "nobody".to_string(), ..email(None) };
+        assert!(matches!(build_message("noreply@ossms.com", &bad), Err(MailError::InvalidAddress { .. })));
+    }
+
+    #[test]
+    fn file_drop_writes_one_eml_per_email() {
+        let dir = std::env::temp_dir().join(format!("ossms-mail-{}", uuid::Uuid::new_v4()));
+        let mailer = FileDropMailer::new(dir.join("mail"), "noreply@ossms.com".to_string());
+
+        block_on(mailer.send(&email(None))).unwrap();
+        block_on(mailer.send(&email(None))).unwrap();
+
+        let files: Vec<PathBuf> = std::fs::read_dir(dir.join("mail")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| file.extension().is_some_and(|ext| ext == "eml")));
+        assert!(contents.contains("To: ann@example.org"));
+    }
+
+    #[test]
+    fn memory_mailer_keeps_what_it_sends() {
+        let mailer = MemoryMailer::default();
+        block_on(mailer.send(&email(None))).unwrap();
+
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject, "Password reset");
+    }
+}
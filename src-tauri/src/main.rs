@@ -2,39 +2,68 @@
 #![cfg_attr(target_os = "windows", windows_subsystem = "windows")]
 
 mod database;
+mod db_pool;
+mod email_templates;
+mod error;
+mod config;
+mod lockout;
+mod mailer;
+mod migrations;
+mod outbox;
+mod password_policy;
+mod permissions;
+mod reset_tokens;
+mod smtp;
+mod stock_digest;
+mod totp;
 
-use database::{Database, User, Supply, SupplyHistory, EnrichedSupplyHistory, UpdateSupplyRequest};
+use lockout::LockoutPolicy;
+use email_templates::{Branding, EmailTemplate};
+use mailer::{MailError, Mailer};
+use outbox::{Outbox, OutboxPolicy};
+use password_policy::PasswordPolicy;
+use reset_tokens::ResetThrottlePolicy;
+use stock_digest::StockDigestPolicy;
+use permissions::{check_grant, check_manage, check_permission, Action, Module, ADMIN_PERMISSIONS};
+use db_pool::DbPool;
+use database::{Database, User, PublicUser, Supply, EnrichedSupplyHistory, UpdateSupplyRequest, StockMovement, StockMovementRequest, SecurityEventFilter, SecurityEventPage, AuditLogFilter, AuditLogPage, IntegrityReport, RolePolicy, SmtpSettings, OutboxEmail, StockDigestSubscription};
+use error::{AppError, AppResult};
+use database::{SESSION_SCOPE_FULL, SESSION_SCOPE_2FA_PENDING, SESSION_SCOPE_2FA_SETUP, SESSION_SCOPE_PASSWORD_CHANGE};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
-use tauri::State;
-use lettre::{Message, SmtpTransport, Transport};
-use lettre::transport::smtp::authentication::Credentials;
+use std::sync::{Arc, RwLock};
+use tauri::{Manager, State};
 use rand::Rng;
 use uuid::Uuid;
 
-// ============================================================================
-// EMAIL CONFIGURATION - UPDATE THESE VALUES FOR YOUR EMAIL SETUP
-// ============================================================================
-// 
-// To set up email password reset functionality:
-// 1. Replace "your-email@gmail.com" with your actual Gmail address
-// 2. Replace "your-app-password" with your Gmail App Password
-// 3. Make sure 2-Factor Authentication is enabled on your Gmail account
-// 4. Generate an App Password from Google Account settings
-//
-// Example:
-// const SMTP_EMAIL: &str = "admin@ust.edu.ph";
-// const SMTP_PASSWORD: &str = "abcd efgh ijkl mnop";
-//
-// ============================================================================
-
-// Hardcoded email configuration
-const SMTP_EMAIL: &str = "jaisenmillard.villegas.cics@ust.edu.ph";  // Replace with your email
-const SMTP_PASSWORD: &str = "jdyivmsvifidepgd";  // Replace with your app password
-
-// App state with connection pooling
+// How long a login session stays valid
+const SESSION_LIFETIME_HOURS: i64 = 12;
+
+// How long the second login step may take, and how long a restricted session
+// (2FA enrollment or forced password change) stays valid
+const TWO_FACTOR_CHALLENGE_MINUTES: i64 = 5;
+const RESTRICTED_SESSION_MINUTES: i64 = 15;
+
+// The only error `login` reports for rejected credentials
+const INVALID_LOGIN_MESSAGE: &str = "Invalid username or password";
+const INVALID_TWO_FACTOR_MESSAGE: &str = "Invalid verification code";
+const INVALID_RESET_TOKEN_MESSAGE: &str = "Invalid or expired reset token";
+const LOGIN_FAILED_MESSAGE: &str = "Sign-in failed, please try again";
+
+// bcrypt hash (default cost) checked against for unknown usernames, so they take as long
+// to reject as a wrong password
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$xBvu0oBu3bnCK8dhVqdwWO355KS6xy.WF3XJMzPQK6/BmpLkNE4ge";
+
+// App state with connection pooling. Managed behind an Arc so commands can hand it to
+// database work running on a blocking thread.
 struct AppState {
-    db: Mutex<Database>,
+    db: DbPool,
+    lockout_policy: LockoutPolicy,
+    password_policy: PasswordPolicy,
+    reset_throttle_policy: ResetThrottlePolicy,
+    // Rebuilt whenever the mail settings change
+    mailer: RwLock<Arc<dyn Mailer>>,
+    outbox: Arc<Outbox>,
+    stock_digest_policy: StockDigestPolicy,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -46,10 +75,99 @@ struct LoginRequest {
 #[derive(Serialize, Deserialize)]
 struct LoginResponse {
     success: bool,
-    user: Option<User>,
+    user: Option<PublicUser>,
+    token: Option<String>,
+    expires_at: Option<String>,
+    // Password accepted; `token` is a challenge for `verify_two_factor`
+    two_factor_required: bool,
+    // Signed in, but the role requires 2FA and the session can only enroll
+    two_factor_setup_required: bool,
+    // Signed in, but the session can only change the password
+    must_change_password: bool,
     error: Option<String>,
 }
 
+impl LoginResponse {
+    fn failure(error: String) -> Self {
+        LoginResponse {
+            success: false,
+            user: None,
+            token: None,
+            expires_at: None,
+            two_factor_required: false,
+            two_factor_setup_required: false,
+            must_change_password: false,
+            error: Some(error),
+        }
+    }
+
+    fn signed_in(user: User, token: String, expires_at: String, scope: &str) -> Self {
+        LoginResponse {
+            success: true,
+            user: Some(user.into()),
+            token: Some(token),
+            expires_at: Some(expires_at),
+            two_factor_required: false,
+            two_factor_setup_required: scope == SESSION_SCOPE_2FA_SETUP,
+            must_change_password: scope == SESSION_SCOPE_PASSWORD_CHANGE,
+            error: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UpdateSmtpSettingsRequest {
+    transport: String,
+    host: String,
+    port: u16,
+    tls_mode: String,
+    username: String,
+    // None keeps the stored password, an empty string clears it
+    password: Option<String>,
+    from_address: String,
+    drop_dir: String,
+    organization_name: String,
+    logo_url: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SetupStatus {
+    needs_setup: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompleteSetupRequest {
+    username: String,
+    password: String,
+    firstname: String,
+    lastname: String,
+    email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AdminResetPasswordRequest {
+    user_id: String,
+    new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VerifyTwoFactorRequest {
+    challenge_token: String,
+    code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
 #[derive(Serialize, Deserialize)]
 struct CreateUserRequest {
     username: String,
@@ -94,290 +212,434 @@ struct ResetPasswordRequest {
     password: String,
 }
 
+// First-run setup: a database without any users needs its first administrator
+#[tauri::command]
+async fn setup_status(state: State<'_, Arc<AppState>>) -> AppResult<SetupStatus> {
+    state.db.read(move |db| {
+        let user_count = db.count_users()?;
+        Ok(SetupStatus { needs_setup: user_count == 0 })
+    }).await
+}
 
+#[tauri::command]
+async fn complete_setup(
+    state: State<'_, Arc<AppState>>,
+    request: CompleteSetupRequest,
+) -> AppResult<String> {
+    state.password_policy.validate(&request.password)?;
+    let hashed_password = hash_password(request.password.clone()).await?;
+    
+    state.db.write(move |db| {
+        // Only allowed while there are no accounts at all
+        let user_count = db.count_users()?;
+        if user_count > 0 {
+            return Err(AppError::conflict("Setup has already been completed"));
+        }
+        
+        let email = check_email(db, &request.email, None)?;
+        
+        let user = User {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: request.username,
+            password: hashed_password,
+            firstname: request.firstname,
+            lastname: request.lastname,
+            email,
+            role: "admin".to_string(),
+            permissions: ADMIN_PERMISSIONS.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        };
+        
+        db.create_user(&user, &user.id, false)?;
+        record_security_event(db, "setup", &user.username, Some(&user.id), "success", None)?;
+        Ok(user.id)
+    }).await
+}
 
-
+#[tauri::command]
+async fn load_demo_data(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let admin = require_admin(db, &session_token)?;
+        
+        // Demo data is meant for trying the app out, never for mixing into a real inventory
+        let supply_count = db.count_supplies()?;
+        if supply_count > 0 {
+            return Err(AppError::conflict("Demo data can only be loaded into an empty inventory"));
+        }
+        
+        db.seed_sample_supplies(&admin.id)?;
+        Ok("Demo data loaded successfully".to_string())
+    }).await
+}
 
 // Optimized API Commands
 #[tauri::command]
 async fn login(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: LoginRequest,
-) -> Result<LoginResponse, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // First, check if user exists
-    match db.get_user_by_username(&request.username) {
-        Ok(Some(user)) => {
-            // Now verify password
-            match db.verify_password(&request.username, &request.password) {
-                Ok(true) => {
-                    Ok(LoginResponse {
-                        success: true,
-                        user: Some(user),
-                        error: None,
-                    })
-                }
-                Ok(false) => {
-                    Ok(LoginResponse {
-                        success: false,
-                        user: None,
-                        error: Some("Invalid credentials".to_string()),
-                    })
-                }
-                Err(e) => {
-                    Ok(LoginResponse {
+) -> AppResult<LoginResponse> {
+    let outcome = authenticate(&state, &request).await;
+    state.db.write(move |db| {
+        match outcome {
+            Ok(LoginOutcome::Success(user)) => {
+                db.cleanup_expired_sessions()?;
+                
+                let totp_state = db.get_totp_state(&user.id)?;
+                if totp_state.enabled {
+                    // Hold back the real session until the second factor is verified
+                    let (challenge, expires_at) = issue_session(db, &user.id, SESSION_SCOPE_2FA_PENDING)?;
+                    record_security_event(db, "login", &request.username, Some(&user.id), "pending", Some("2fa_required"))?;
+                    
+                    return Ok(LoginResponse {
                         success: false,
                         user: None,
-                        error: Some(format!("Database error: {}", e)),
-                    })
+                        token: Some(challenge),
+                        expires_at: Some(expires_at),
+                        two_factor_required: true,
+                        two_factor_setup_required: false,
+                        must_change_password: false,
+                        error: None,
+                    });
                 }
+                
+                let scope = signed_in_scope(db, &user)?;
+                let (token, expires_at) = issue_session(db, &user.id, scope)?;
+                let reason = (scope != SESSION_SCOPE_FULL).then_some(scope);
+                record_security_event(db, "login", &request.username, Some(&user.id), "success", reason)?;
+                
+                Ok(LoginResponse::signed_in(user, token, expires_at, scope))
+            }
+            // Unknown users, wrong passwords and locked accounts all look the same to the caller
+            Ok(LoginOutcome::Rejected { user_id, reason }) => {
+                record_security_event(db, "login", &request.username, user_id.as_deref(), "failure", Some(reason))?;
+                Ok(LoginResponse::failure(INVALID_LOGIN_MESSAGE.to_string()))
+            }
+            // The details go to the security event log, not the login screen
+            Err(e) => {
+                let _ = record_security_event(db, "login", &request.username, None, "error", Some(&e.message));
+                Ok(LoginResponse::failure(LOGIN_FAILED_MESSAGE.to_string()))
             }
         }
-        Ok(None) => {
-            Ok(LoginResponse {
-                success: false,
-                user: None,
-                error: Some("User not found".to_string()),
-            })
+    }).await
+}
+
+#[tauri::command]
+async fn verify_two_factor(
+    state: State<'_, Arc<AppState>>,
+    request: VerifyTwoFactorRequest,
+) -> AppResult<LoginResponse> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        let user = match db.get_session(&request.challenge_token)? {
+            Some((user, scope)) if scope == SESSION_SCOPE_2FA_PENDING => user,
+            _ => return Ok(LoginResponse::failure("Verification expired, please sign in again".to_string())),
+        };
+        
+        // Code guesses count toward the same lockout as password guesses
+        let locked = db.get_login_lockout(&user.username, Some(&user.id))?;
+        if locked.is_some() {
+            db.delete_session(&request.challenge_token)?;
+            record_security_event(db, "two_factor", &user.username, Some(&user.id), "failure", Some("locked_out"))?;
+            return Ok(LoginResponse::failure(INVALID_TWO_FACTOR_MESSAGE.to_string()));
         }
-        Err(e) => {
-            Ok(LoginResponse {
-                success: false,
-                user: None,
-                error: Some(format!("Database error: {}", e)),
-            })
+        
+        match check_second_factor(db, &user, &request.code)? {
+            Some(method) => {
+                db.delete_session(&request.challenge_token)?;
+                db.clear_failed_logins(&user.username, Some(&user.id))?;
+                
+                let scope = signed_in_scope(db, &user)?;
+                let (token, expires_at) = issue_session(db, &user.id, scope)?;
+                record_security_event(db, "two_factor", &user.username, Some(&user.id), "success", Some(method))?;
+                
+                Ok(LoginResponse::signed_in(user, token, expires_at, scope))
+            }
+            None => {
+                let failures = db.record_failed_login(&user.username, Some(&user.id))?;
+                if let Some(lockout) = app.lockout_policy.lockout_for(failures) {
+                    let locked_until = (chrono::Utc::now() + lockout).to_rfc3339();
+                    db.lock_login(&user.username, Some(&user.id), &locked_until)?;
+                    db.delete_session(&request.challenge_token)?;
+                }
+                record_security_event(db, "two_factor", &user.username, Some(&user.id), "failure", Some("bad_code"))?;
+                Ok(LoginResponse::failure(INVALID_TWO_FACTOR_MESSAGE.to_string()))
+            }
         }
+    }).await
+}
+
+// Result of a login attempt; the rejection reason only goes to the security event log
+enum LoginOutcome {
+    Success(User),
+    Rejected {
+        user_id: Option<String>,
+        reason: &'static str,
+    },
+}
+
+// Check credentials while enforcing the lockout policy. The password is checked outside
+// the pool, so the writer is only taken for the bookkeeping afterwards.
+async fn authenticate(state: &Arc<AppState>, request: &LoginRequest) -> AppResult<LoginOutcome> {
+    let username = request.username.clone();
+    let (user, locked) = state.db.read(move |db| {
+        let user = db.get_user_by_username(&username)?;
+        let locked = db.get_login_lockout(&username, user.as_ref().map(|u| u.id.as_str()))?.is_some();
+        Ok((user, locked))
+    }).await?;
+    let user_id = user.as_ref().map(|u| u.id.clone());
+    
+    // Locked out: reject without even checking the password
+    if locked {
+        return Ok(LoginOutcome::Rejected { user_id, reason: "locked_out" });
     }
+    
+    let password_hash = user.as_ref().map_or(DUMMY_PASSWORD_HASH, |u| u.password.as_str()).to_string();
+    let verified = password_matches(request.password.clone(), vec![password_hash]).await? && user.is_some();
+    
+    let app = Arc::clone(state);
+    let username = request.username.clone();
+    state.db.write(move |db| {
+        let user = match user {
+            Some(user) if verified => user,
+            _ => {
+                let failures = db.record_failed_login(&username, user_id.as_deref())?;
+                let mut reason = if user_id.is_some() { "bad_password" } else { "unknown_user" };
+                if let Some(lockout) = app.lockout_policy.lockout_for(failures) {
+                    let locked_until = (chrono::Utc::now() + lockout).to_rfc3339();
+                    db.lock_login(&username, user_id.as_deref(), &locked_until)?;
+                    reason = if user_id.is_some() { "bad_password_locked" } else { "unknown_user_locked" };
+                }
+                return Ok(LoginOutcome::Rejected { user_id, reason });
+            }
+        };
+        
+        db.clear_failed_logins(&username, Some(&user.id))?;
+        Ok(LoginOutcome::Success(user))
+    }).await
 }
 
 #[tauri::command]
-async fn get_users(state: State<'_, AppState>) -> Result<Vec<User>, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    db.get_users().map_err(|e| format!("Database error: {}", e))
+async fn get_users(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<Vec<PublicUser>> {
+    state.db.read(move |db| {
+        authorize(db, &session_token, Module::Users, Action::View)?;
+        let users = db.get_users()?;
+        Ok(users.into_iter().map(PublicUser::from).collect())
+    }).await
 }
 
 #[tauri::command]
 async fn create_user(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: CreateUserRequest,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed user_id
-    let current_user = db.get_user_by_id(&user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
-    
-    let user = User {
-        id: uuid::Uuid::new_v4().to_string(),
-        username: request.username,
-        password: request.password,
-        firstname: request.firstname,
-        lastname: request.lastname,
-        email: request.email,
-        role: request.role,
-        permissions: request.permissions,
-        created_at: chrono::Utc::now().to_rfc3339(),
-        updated_at: chrono::Utc::now().to_rfc3339(),
-    };
+    session_token: String,
+) -> AppResult<String> {
+    // Resolve the acting user from the session and check their permissions
+    let session = session_token.clone();
+    let (role, permissions) = (request.role.clone(), request.permissions.clone());
+    state.db.read(move |db| {
+        let current_user = authorize(db, &session, Module::Users, Action::Create)?;
+        check_grant(&current_user, None, &role, &permissions)
+    }).await?;
+    state.password_policy.validate(&request.password)?;
+    let hashed_password = hash_password(request.password.clone()).await?;
     
-    db.create_user(&user, &user_id_for_history).map_err(|e| format!("Database error: {}", e))
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        let user_id_for_history = authorize(db, &session_token, Module::Users, Action::Create)?.id;
+        let email = check_email(db, &request.email, None)?;
+        
+        let user = User {
+            id: uuid::Uuid::new_v4().to_string(),
+            username: request.username,
+            password: hashed_password,
+            firstname: request.firstname,
+            lastname: request.lastname,
+            email,
+            role: request.role,
+            permissions: request.permissions,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        };
+        
+        // The admin chose this password, so the user has to replace it on first login
+        let new_user_id = db.create_user(&user, &user_id_for_history, true)?;
+        
+        queue_email(&app, db, EmailTemplate::Welcome, &user.email, &[
+            ("firstname", &user.firstname),
+            ("username", &user.username),
+        ], None)?;
+        Ok(new_user_id)
+    }).await
 }
 
 #[tauri::command]
 async fn update_user(
-    state: State<'_, AppState>,
-    request: database::UpdateUserRequest,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed user_id
-    let current_user = db.get_user_by_id(&user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
-    
-    // Update the user directly by ID
-    db.update_user(&request.id, &request, &user_id_for_history).map_err(|e| format!("Database error: {}", e))?;
-    Ok("User updated successfully".to_string())
+    state: State<'_, Arc<AppState>>,
+    mut request: database::UpdateUserRequest,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        // Resolve the acting user from the session and check their permissions
+        let current_user = authorize(db, &session_token, Module::Users, Action::Edit)?;
+        let target = db.get_user_by_id(&request.id)?.ok_or_else(|| AppError::not_found("User not found"))?;
+        check_grant(&current_user, Some(&target), &request.role, &request.permissions)?;
+        let user_id_for_history = current_user.id;
+        request.email = check_email(db, &request.email, Some(&request.id))?;
+        
+        // Update the user directly by ID
+        db.update_user(&request.id, &request, &user_id_for_history)?;
+        Ok("User updated successfully".to_string())
+    }).await
 }
 
 #[tauri::command]
-async fn get_supplies(state: State<'_, AppState>) -> Result<Vec<Supply>, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    db.get_supplies().map_err(|e| format!("Database error: {}", e))
+async fn get_supplies(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<Vec<Supply>> {
+    state.db.read(move |db| {
+        authorize(db, &session_token, Module::Supplies, Action::View)?;
+        db.get_supplies()
+    }).await
 }
 
 #[tauri::command]
 async fn create_supply(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: CreateSupplyRequest,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed user_id
-    let current_user = db.get_user_by_id(&user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
-    
-    let supply = Supply {
-        id: uuid::Uuid::new_v4().to_string(),
-        name: request.name,
-        description: request.description,
-        category: request.category,
-        subcategory: request.subcategory,
-        variation: request.variation,
-        brand: request.brand,
-        quantity: request.quantity,
-        unit: request.unit,
-        min_quantity: request.min_quantity,
-        status: request.status,
-        location: request.location,
-        supplier: request.supplier,
-        supplier_name: request.supplier_name,
-        supplier_contact: request.supplier_contact,
-        supplier_notes: request.supplier_notes,
-        cost: request.cost,
-        pieces_per_bulk: request.pieces_per_bulk,
-        created_at: chrono::Utc::now().to_rfc3339(),
-        updated_at: chrono::Utc::now().to_rfc3339(),
-    };
-    
-    db.create_supply(&supply, &user_id_for_history).map_err(|e| format!("Database error: {}", e))
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        // Resolve the acting user from the session and check their permissions
+        let user_id_for_history = authorize(db, &session_token, Module::Supplies, Action::Create)?.id;
+        
+        let supply = Supply {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: request.name,
+            description: request.description,
+            category: request.category,
+            subcategory: request.subcategory,
+            variation: request.variation,
+            brand: request.brand,
+            quantity: request.quantity,
+            unit: request.unit,
+            min_quantity: request.min_quantity,
+            status: request.status,
+            location: request.location,
+            supplier: request.supplier,
+            supplier_name: request.supplier_name,
+            supplier_contact: request.supplier_contact,
+            supplier_notes: request.supplier_notes,
+            cost: request.cost,
+            pieces_per_bulk: request.pieces_per_bulk,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            version: 1,
+        };
+        
+        db.create_supply(&supply, &user_id_for_history)
+    }).await
 }
 
 #[tauri::command]
 async fn update_supply(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: UpdateSupplyRequest,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed user_id
-    let current_user = db.get_user_by_id(&user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
-    
-    // Get current supply to calculate quantity changes
-    let current_supply = db.get_supply_by_id(&request.id).map_err(|e| format!("Failed to get supply: {}", e))?;
-    let current_quantity = current_supply.quantity;
-    let new_quantity = request.quantity.unwrap_or(current_quantity);
-    
-    // Update the supply
-    db.update_supply(&request.id, &request).map_err(|e| format!("Failed to update supply: {}", e))?;
-    
-    // Check if item details were modified (excluding quantity)
-    let item_details_changed = 
-        request.name.is_some() || 
-        request.description.is_some() || 
-        request.category.is_some() || 
-        request.subcategory.is_some() ||
-        request.variation.is_some() ||
-        request.brand.is_some() ||
-        request.unit.is_some() || 
-        request.min_quantity.is_some() || 
-        request.status.is_some() || 
-        request.location.is_some() || 
-        request.supplier.is_some() || 
-        request.cost.is_some();
-
-    // If quantity changed, create a stock history record
-    if new_quantity != current_quantity {
-        let action = if new_quantity > current_quantity { "Stock In" } else { "Stock Out" };
-        let quantity_change = (new_quantity - current_quantity).abs();
-        
-        // Determine the appropriate reason based on the action
-        let notes = if new_quantity > current_quantity {
-            request.stock_in_reason.clone().unwrap_or_else(|| "Stock added".to_string())
-        } else {
-            request.stock_out_reason.clone().unwrap_or_else(|| "Stock released".to_string())
-        };
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        // Resolve the acting user from the session and check their permissions
+        let user_id_for_history = authorize(db, &session_token, Module::Supplies, Action::Edit)?.id;
         
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: request.id.clone(),
-            action: action.to_string(),
-            quantity: quantity_change,
-            previous_quantity: current_quantity,
-            new_quantity: new_quantity,
-            notes: Some(notes),
-            user_id: user_id_for_history,
-            created_at: chrono::Utc::now().to_rfc3339(),
-        };
+        // Update the supply and record the change in its history
+        db.update_supply(&request.id, &request, &user_id_for_history)?;
         
-        db.create_supply_history(&history).map_err(|e| format!("Failed to create history record: {}", e))?;
-    }
-    // If only item details changed (no quantity change), create an "Item Updated" history record
-    else if item_details_changed {
-        let history = SupplyHistory {
-            id: uuid::Uuid::new_v4().to_string(),
-            supply_id: request.id.clone(),
-            action: "Item Updated".to_string(),
-            quantity: 0,
-            previous_quantity: current_quantity,
-            new_quantity: current_quantity,
-            notes: Some("Item details updated".to_string()),
-            user_id: user_id_for_history,
-            created_at: chrono::Utc::now().to_rfc3339(),
-        };
-        
-        db.create_supply_history(&history).map_err(|e| format!("Failed to create history record: {}", e))?;
-    }
-    
-    Ok("Supply updated successfully".to_string())
+        Ok("Supply updated successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn stock_in(
+    state: State<'_, Arc<AppState>>,
+    request: StockMovementRequest,
+    session_token: String,
+) -> AppResult<Supply> {
+    state.db.write(move |db| {
+        let user_id = authorize(db, &session_token, Module::Supplies, Action::Edit)?.id;
+        db.apply_stock_movement(StockMovement::In, &request, &user_id)
+    }).await
+}
+
+// Refused with a conflict if it would take the quantity below zero
+#[tauri::command]
+async fn stock_out(
+    state: State<'_, Arc<AppState>>,
+    request: StockMovementRequest,
+    session_token: String,
+) -> AppResult<Supply> {
+    state.db.write(move |db| {
+        let user_id = authorize(db, &session_token, Module::Supplies, Action::Edit)?.id;
+        db.apply_stock_movement(StockMovement::Out, &request, &user_id)
+    }).await
+}
+
+// `quantity` is signed: positive adds stock, negative removes it
+#[tauri::command]
+async fn adjust_stock(
+    state: State<'_, Arc<AppState>>,
+    request: StockMovementRequest,
+    session_token: String,
+) -> AppResult<Supply> {
+    state.db.write(move |db| {
+        let user_id = authorize(db, &session_token, Module::Supplies, Action::Edit)?.id;
+        db.apply_stock_movement(StockMovement::Adjust, &request, &user_id)
+    }).await
 }
 
 #[tauri::command]
-async fn get_supply_histories(state: State<'_, AppState>) -> Result<Vec<EnrichedSupplyHistory>, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    db.get_supply_histories().map_err(|e| format!("Database error: {}", e))
+async fn get_supply_histories(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<Vec<EnrichedSupplyHistory>> {
+    state.db.read(move |db| {
+        authorize(db, &session_token, Module::SupplyHistories, Action::View)?;
+        db.get_supply_histories()
+    }).await
 }
 
 #[tauri::command]
 async fn delete_supply(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     supply_id: String,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    db.delete_supply(&supply_id, &user_id).map_err(|e| format!("Database error: {}", e))?;
-    Ok("Supply deleted successfully".to_string())
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let user = authorize(db, &session_token, Module::Supplies, Action::Delete)?;
+        db.delete_supply(&supply_id, &user.id)?;
+        Ok("Supply deleted successfully".to_string())
+    }).await
 }
 
 #[tauri::command]
 async fn delete_supply_history(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     history_id: String,
-    user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed user_id
-    let current_user = db.get_user_by_id(&user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
-    
-    db.delete_supply_history(&history_id, &user_id_for_history).map_err(|e| format!("Database error: {}", e))?;
-    Ok("Supply history deleted successfully".to_string())
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        // Resolve the acting user from the session and check their permissions
+        let user_id_for_history = authorize(db, &session_token, Module::SupplyHistories, Action::Delete)?.id;
+        
+        db.delete_supply_history(&history_id, &user_id_for_history)?;
+        Ok("Supply history deleted successfully".to_string())
+    }).await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -394,53 +656,52 @@ struct ResetPasswordResponse {
 
 #[tauri::command]
 async fn forgot_password(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: ForgotPasswordRequest,
-) -> Result<ForgotPasswordResponse, String> {
-    // Debug logging removed for production
+) -> AppResult<ForgotPasswordResponse> {
+    let email = request.email.trim().to_string();
     
-    // First, get the user and create the token while holding the lock
-    let (user, token) = {
-        let db = state.db.lock().map_err(|_| "Database lock failed")?;
-        
+    // Every branch ends in the same response so the caller can't tell whether the
+    // address belongs to an account. The email itself is sent by the outbox worker.
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
         // Clean up expired tokens first
-        db.cleanup_expired_tokens().map_err(|e| format!("Failed to cleanup tokens: {}", e))?;
+        db.cleanup_expired_tokens()?;
         
-        // Check if user exists with the provided email
-        match db.get_user_by_email(&request.email) {
-            Ok(Some(user)) => {
-                // Generate a reset token
-                let token = generate_reset_token();
-                
-                // Set expiration to 1 hour from now
-                let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
-                let expires_at_str = expires_at.to_rfc3339();
-                
-                // Store the token in the database
-                db.create_password_reset_token(&user.id, &token, &expires_at_str)
-                    .map_err(|e| format!("Failed to create reset token: {}", e))?;
-                
-                Ok::<(database::User, String), String>((user, token))
-            }
-            Ok(None) => {
-                return Ok(ForgotPasswordResponse { 
-                    success: false, 
-                    error: Some("No user found with this email address".to_string()) 
-                });
-            }
-            Err(e) => {
-                return Ok(ForgotPasswordResponse { 
-                    success: false, 
-                    error: Some(format!("Database error: {}", e)) 
-                });
+        let policy = &app.reset_throttle_policy;
+        let since = (chrono::Utc::now() - chrono::Duration::seconds(policy.window_secs)).to_rfc3339();
+        let email_requests = db.count_reset_requests(Some(&email), &since)?;
+        let all_requests = db.count_reset_requests(None, &since)?;
+        
+        if email_requests >= policy.max_per_email || all_requests >= policy.max_global {
+            record_security_event(db, "password_reset_request", &email, None, "failure", Some("throttled"))?;
+        } else {
+            match db.get_user_by_email(&email)? {
+                Some(user) => {
+                    let token = reset_tokens::generate_token();
+                    
+                    // Set expiration to 1 hour from now
+                    let expires_at = (chrono::Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+                    
+                    // Only the hash is stored; the plain token goes out by email, and the
+                    // queued email is dropped when the token expires
+                    db.create_password_reset_token(&user.id, &reset_tokens::hash_token(&token), &expires_at)?;
+                    
+                    queue_email(&app, db, EmailTemplate::PasswordReset, &email, &[
+                        ("username", &user.username),
+                        ("token", &token),
+                        ("expires_in", "1 hour"),
+                    ], Some(&expires_at))?;
+                    
+                    record_security_event(db, "password_reset_request", &email, Some(&user.id), "success", None)?;
+                }
+                None => {
+                    record_security_event(db, "password_reset_request", &email, None, "failure", Some("unknown_email"))?;
+                }
             }
         }
-    }?;
-    
-    // Now send the email without holding the database lock
-    send_reset_email(&request.email, &token, &user.username)
-        .await
-        .map_err(|e| format!("Failed to send email: {}", e))?;
+        Ok(())
+    }).await?;
     
     Ok(ForgotPasswordResponse { 
         success: true, 
@@ -450,172 +711,815 @@ async fn forgot_password(
 
 #[tauri::command]
 async fn reset_password(
-    state: State<'_, AppState>,
+    state: State<'_, Arc<AppState>>,
     request: ResetPasswordRequest,
-) -> Result<ResetPasswordResponse, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Validate the reset token
-    let token_data = db.get_password_reset_token(&request.token)
-        .map_err(|e| format!("Failed to get token: {}", e))?;
-    
-    match token_data {
-        Some(token) => {
-            // Check if token is expired
-            let now = chrono::Utc::now();
-            let expires_at = chrono::DateTime::parse_from_rfc3339(&token.expires_at)
-                .map_err(|e| format!("Invalid token expiration date: {}", e))?;
-            
-            if now > expires_at {
-                return Ok(ResetPasswordResponse { 
-                    success: false, 
-                    error: Some("Reset token has expired".to_string()) 
-                });
-            }
-            
-            // Check if token is already used
-            if token.used {
-                return Ok(ResetPasswordResponse { 
-                    success: false, 
-                    error: Some("Reset token has already been used".to_string()) 
-                });
-            }
-            
-            // Verify the email matches the token's user
-            let user = db.get_user_by_email(&request.email)
-                .map_err(|e| format!("Failed to get user: {}", e))?;
-            
-            match user {
-                Some(user) => {
-                    if user.id != token.user_id {
-                        return Ok(ResetPasswordResponse { 
-                            success: false, 
-                            error: Some("Invalid token for this email address".to_string()) 
-                        });
-                    }
-                    
-                    // Hash the new password
-                    let hashed_password = bcrypt::hash(&request.password, bcrypt::DEFAULT_COST)
-                        .map_err(|e| format!("Password hashing error: {}", e))?;
-                    
-                    // Update the user's password
-                    db.update_user_password(&user.id, &hashed_password)
-                        .map_err(|e| format!("Failed to update password: {}", e))?;
-                    
-                    // Mark the token as used
-                    db.mark_token_as_used(&request.token)
-                        .map_err(|e| format!("Failed to mark token as used: {}", e))?;
-                    
-                    Ok(ResetPasswordResponse { 
-                        success: true, 
-                        error: None 
-                    })
-                }
-                None => {
-                    Ok(ResetPasswordResponse { 
-                        success: false, 
-                        error: Some("No user found with this email address".to_string()) 
-                    })
-                }
-            }
+) -> AppResult<ResetPasswordResponse> {
+    let invalid_token = || ResetPasswordResponse { 
+        success: false, 
+        error: Some(INVALID_RESET_TOKEN_MESSAGE.to_string()) 
+    };
+    
+    // Unknown addresses get the same answer as a wrong token
+    let email = request.email.trim().to_string();
+    let user = match state.db.read(move |db| db.get_user_by_email(&email)).await? {
+        Some(user) => user,
+        None => {
+            log_security_event(&state, "password_reset", &request.email, None, "failure", Some("unknown_email")).await?;
+            return Ok(invalid_token());
         }
+    };
+    
+    // Tokens are only stored hashed, so compare against each of the user's tokens
+    let user_id = user.id.clone();
+    let token = state.db.read(move |db| db.get_password_reset_tokens_for_user(&user_id)).await?
+        .into_iter()
+        .find(|token| reset_tokens::token_matches(&request.token, &token.token));
+    
+    let token = match token {
+        Some(token) => token,
         None => {
-            Ok(ResetPasswordResponse { 
-                success: false, 
-                error: Some("Invalid or expired reset token".to_string()) 
-            })
+            log_security_event(&state, "password_reset", &request.email, Some(&user.id), "failure", Some("invalid_token")).await?;
+            return Ok(invalid_token());
         }
+    };
+    
+    // Check if token is expired
+    let now = chrono::Utc::now();
+    let expires_at = chrono::DateTime::parse_from_rfc3339(&token.expires_at)
+        .map_err(|e| AppError::internal(format!("Invalid token expiration date: {}", e)))?;
+    
+    if now > expires_at {
+        log_security_event(&state, "password_reset", &request.email, Some(&user.id), "failure", Some("expired_token")).await?;
+        return Ok(invalid_token());
+    }
+    
+    // Check if token is already used
+    if token.used {
+        log_security_event(&state, "password_reset", &request.email, Some(&user.id), "failure", Some("used_token")).await?;
+        return Ok(invalid_token());
     }
+    
+    // Enforce the password policy, including reuse of recent passwords
+    if let Err(e) = check_new_password(&state, &user.id, &request.password).await {
+        log_security_event(&state, "password_reset", &request.email, Some(&user.id), "failure", Some("policy_violation")).await?;
+        return Ok(ResetPasswordResponse { 
+            success: false, 
+            error: Some(e.message) 
+        });
+    }
+    
+    // Hash the new password
+    let hashed_password = hash_password(request.password.clone()).await?;
+    
+    state.db.write(move |db| {
+        // Another reset may have used the token while the password was hashed
+        let still_unused = db.get_password_reset_tokens_for_user(&user.id)?
+            .iter()
+            .any(|t| t.id == token.id && !t.used);
+        if !still_unused {
+            record_security_event(db, "password_reset", &request.email, Some(&user.id), "failure", Some("used_token"))?;
+            return Ok(invalid_token());
+        }
+        
+        // Update the password and use up this token along with any others the user holds
+        db.complete_password_reset(&user.id, &hashed_password)?;
+        
+        record_security_event(db, "password_reset", &request.email, Some(&user.id), "success", None)?;
+        
+        Ok(ResetPasswordResponse { 
+            success: true, 
+            error: None 
+        })
+    }).await
 }
 
 #[tauri::command]
-async fn delete_user(
-    state: State<'_, AppState>,
-    user_id: String,
-    current_user_id: String,
-) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
-    
-    // Get current user from the passed current_user_id
-    let current_user = db.get_user_by_id(&current_user_id).map_err(|e| format!("Failed to get current user: {}", e))?;
-    let user_id_for_history = if let Some(user) = current_user {
-        user.id
-    } else {
-        return Err("Current user not found".to_string());
-    };
+async fn change_password(
+    state: State<'_, Arc<AppState>>,
+    request: ChangePasswordRequest,
+    session_token: String,
+) -> AppResult<String> {
+    let session = session_token.clone();
+    let (user, _) = state.db.read(move |db| {
+        require_session_scope(db, &session, &[SESSION_SCOPE_FULL, SESSION_SCOPE_PASSWORD_CHANGE])
+    }).await?;
     
-    // In a real application, you might want to:
-    // 1. Check if user exists
-    // 2. Check if user has any associated data
-    // 3. Soft delete instead of hard delete
+    let current_ok = password_matches(request.current_password.clone(), vec![user.password.clone()]).await?;
+    if !current_ok {
+        log_security_event(&state, "password_change", &user.username, Some(&user.id), "failure", Some("bad_password")).await?;
+        return Err(AppError::validation("Current password is incorrect"));
+    }
     
-    // For now, we'll just delete the user
-    db.delete_user(&user_id, &user_id_for_history).map_err(|e| format!("Database error: {}", e))?;
-    Ok("User deleted successfully".to_string())
+    if let Err(e) = check_new_password(&state, &user.id, &request.new_password).await {
+        log_security_event(&state, "password_change", &user.username, Some(&user.id), "failure", Some("policy_violation")).await?;
+        return Err(e);
+    }
+    
+    let hashed_password = hash_password(request.new_password.clone()).await?;
+    
+    state.db.write(move |db| {
+        // The session may have ended while the password was checked
+        let (user, scope) = require_session_scope(db, &session_token, &[SESSION_SCOPE_FULL, SESSION_SCOPE_PASSWORD_CHANGE])?;
+        
+        db.update_user_password(&user.id, &hashed_password)?;
+        
+        db.set_must_change_password(&user.id, false)?;
+        
+        // Keep this session, sign out everywhere else
+        db.delete_other_sessions(&user.id, &session_token)?;
+        
+        // A password-change-only session gets whatever access the user now has
+        if scope == SESSION_SCOPE_PASSWORD_CHANGE {
+            upgrade_session(db, &session_token, &user)?;
+        }
+        
+        record_security_event(db, "password_change", &user.username, Some(&user.id), "success", None)?;
+        Ok("Password changed successfully".to_string())
+    }).await
 }
 
 #[tauri::command]
-async fn recalculate_stock_status(state: State<'_, AppState>) -> Result<String, String> {
-    let db = state.db.lock().map_err(|_| "Database lock failed")?;
+async fn admin_reset_password(
+    state: State<'_, Arc<AppState>>,
+    request: AdminResetPasswordRequest,
+    session_token: String,
+) -> AppResult<String> {
+    let session = session_token.clone();
+    let target_id = request.user_id.clone();
+    state.db.read(move |db| {
+        let admin = authorize(db, &session, Module::Users, Action::Edit)?;
+        let user = db.get_user_by_id(&target_id)?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+        check_manage(&admin, &user)
+    }).await?;
     
-    match db.recalculate_all_stock_status() {
-        Ok(_) => Ok("Stock status recalculated successfully".to_string()),
-        Err(e) => Err(format!("Failed to recalculate stock status: {}", e)),
-    }
+    state.password_policy.validate(&request.new_password)?;
+    let hashed_password = hash_password(request.new_password.clone()).await?;
+    
+    state.db.write(move |db| {
+        let admin = authorize(db, &session_token, Module::Users, Action::Edit)?;
+        
+        let user = db.get_user_by_id(&request.user_id)?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+        check_manage(&admin, &user)?;
+        
+        db.update_user_password(&user.id, &hashed_password)?;
+        
+        // The admin knows this password, so the user has to replace it on next login
+        db.set_must_change_password(&user.id, true)?;
+        db.delete_sessions_for_user(&user.id)?;
+        
+        record_security_event(db, "password_reset", &user.username, Some(&user.id), "success", Some(&format!("reset by {}", admin.username)))?;
+        Ok("Password reset successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn delete_user(
+    state: State<'_, Arc<AppState>>,
+    user_id: String,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        // Resolve the acting user from the session and check their permissions
+        let current_user = authorize(db, &session_token, Module::Users, Action::Delete)?;
+        let target = db.get_user_by_id(&user_id)?.ok_or_else(|| AppError::not_found("User not found"))?;
+        check_manage(&current_user, &target)?;
+        
+        db.delete_user(&user_id, &current_user.id)?;
+        Ok("User deleted successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn logout(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        db.delete_session(&session_token)?;
+        Ok("Logged out successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn revoke_user_sessions(
+    state: State<'_, Arc<AppState>>,
+    user_id: String,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let current_user = require_session(db, &session_token)?;
+        
+        // Users may sign themselves out everywhere; doing it for others needs user edit rights
+        if current_user.id != user_id {
+            check_permission(&current_user, Module::Users, Action::Edit)?;
+        }
+        
+        let revoked = db.delete_sessions_for_user(&user_id)?;
+        Ok(format!("Revoked {} session(s)", revoked))
+    }).await
+}
+
+#[tauri::command]
+async fn unlock_user(
+    state: State<'_, Arc<AppState>>,
+    user_id: String,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let current_user = authorize(db, &session_token, Module::Users, Action::Edit)?;
+        let target = db.get_user_by_id(&user_id)?.ok_or_else(|| AppError::not_found("User not found"))?;
+        check_manage(&current_user, &target)?;
+        
+        db.unlock_user(&user_id)?;
+        Ok("User unlocked successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn get_security_events(
+    state: State<'_, Arc<AppState>>,
+    filter: SecurityEventFilter,
+    session_token: String,
+) -> AppResult<SecurityEventPage> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.get_security_events(&filter)
+    }).await
+}
+
+#[tauri::command]
+async fn get_audit_log(
+    state: State<'_, Arc<AppState>>,
+    filter: AuditLogFilter,
+    session_token: String,
+) -> AppResult<AuditLogPage> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.get_audit_log(&filter)
+    }).await
+}
+
+#[tauri::command]
+async fn check_database_integrity(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<IntegrityReport> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.check_integrity()
+    }).await
+}
+
+#[tauri::command]
+async fn begin_totp_enrollment(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<TotpEnrollment> {
+    state.db.write(move |db| {
+        let (user, _) = require_session_scope(db, &session_token, &[SESSION_SCOPE_FULL, SESSION_SCOPE_2FA_SETUP])?;
+        
+        let totp_state = db.get_totp_state(&user.id)?;
+        if totp_state.enabled {
+            return Err(AppError::conflict("Two-factor authentication is already enabled"));
+        }
+        
+        let secret = totp::generate_secret().map_err(AppError::internal)?;
+        let otpauth_uri = totp::provisioning_uri(&secret, &user.username).map_err(AppError::internal)?;
+        db.set_pending_totp_secret(&user.id, &secret)?;
+        
+        Ok(TotpEnrollment { secret, otpauth_uri })
+    }).await
+}
+
+#[tauri::command]
+async fn confirm_totp_enrollment(
+    state: State<'_, Arc<AppState>>,
+    code: String,
+    session_token: String,
+) -> AppResult<Vec<String>> {
+    state.db.write(move |db| {
+        let (user, scope) = require_session_scope(db, &session_token, &[SESSION_SCOPE_FULL, SESSION_SCOPE_2FA_SETUP])?;
+        
+        let totp_state = db.get_totp_state(&user.id)?;
+        if totp_state.enabled {
+            return Err(AppError::conflict("Two-factor authentication is already enabled"));
+        }
+        let secret = totp_state.secret.ok_or_else(|| AppError::conflict("Start two-factor enrollment first"))?;
+        
+        let step = totp::verify_code(&secret, &user.username, &code, None).map_err(AppError::internal)?
+            .ok_or_else(|| AppError::validation(INVALID_TWO_FACTOR_MESSAGE))?;
+        db.enable_totp(&user.id, step)?;
+        let recovery_codes = new_recovery_codes(db, &user.id)?;
+        
+        // An enrollment-only session becomes a normal one once 2FA is on
+        if scope == SESSION_SCOPE_2FA_SETUP {
+            upgrade_session(db, &session_token, &user)?;
+        }
+        
+        record_security_event(db, "two_factor_enrolled", &user.username, Some(&user.id), "success", None)?;
+        Ok(recovery_codes)
+    }).await
+}
+
+#[tauri::command]
+async fn regenerate_recovery_codes(
+    state: State<'_, Arc<AppState>>,
+    code: String,
+    session_token: String,
+) -> AppResult<Vec<String>> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        let user = require_session(db, &session_token)?;
+        confirm_second_factor(db, &app.lockout_policy, &user, &code)?;
+        
+        new_recovery_codes(db, &user.id)
+    }).await
+}
+
+#[tauri::command]
+async fn disable_totp(
+    state: State<'_, Arc<AppState>>,
+    code: String,
+    session_token: String,
+) -> AppResult<String> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        let user = require_session(db, &session_token)?;
+        
+        if db.role_requires_2fa(&user.role)? {
+            return Err(AppError::forbidden("Two-factor authentication is required for your role"));
+        }
+        confirm_second_factor(db, &app.lockout_policy, &user, &code)?;
+        
+        db.disable_totp(&user.id)?;
+        record_security_event(db, "two_factor_disabled", &user.username, Some(&user.id), "success", None)?;
+        Ok("Two-factor authentication disabled".to_string())
+    }).await
+}
+
+// Admin recovery for users who lost their authenticator and recovery codes
+#[tauri::command]
+async fn reset_user_two_factor(
+    state: State<'_, Arc<AppState>>,
+    user_id: String,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let admin = require_admin(db, &session_token)?;
+        
+        let user = db.get_user_by_id(&user_id)?
+            .ok_or_else(|| AppError::not_found("User not found"))?;
+        
+        db.disable_totp(&user.id)?;
+        record_security_event(db, "two_factor_reset", &user.username, Some(&user.id), "success", Some(&format!("reset by {}", admin.username)))?;
+        Ok("Two-factor authentication reset".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn get_role_policies(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<Vec<RolePolicy>> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.get_role_policies()
+    }).await
+}
+
+#[tauri::command]
+async fn set_role_two_factor_requirement(
+    state: State<'_, Arc<AppState>>,
+    role: String,
+    required: bool,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        require_admin(db, &session_token)?;
+        db.set_role_requires_2fa(&role, required)?;
+        Ok("Role policy updated successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn get_smtp_settings(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<smtp::MaskedSmtpSettings> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        Ok(smtp::masked(&load_smtp_settings(db)?))
+    }).await
+}
+
+#[tauri::command]
+async fn update_smtp_settings(
+    state: State<'_, Arc<AppState>>,
+    request: UpdateSmtpSettingsRequest,
+    session_token: String,
+) -> AppResult<smtp::MaskedSmtpSettings> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        let admin = require_admin(db, &session_token)?;
+        
+        let stored = db.get_smtp_settings()?
+            .unwrap_or_default();
+        let settings = SmtpSettings {
+            transport: request.transport.trim().to_lowercase(),
+            host: request.host.trim().to_string(),
+            port: request.port,
+            tls_mode: request.tls_mode.trim().to_lowercase(),
+            username: request.username.trim().to_string(),
+            password: request.password.unwrap_or(stored.password),
+            from_address: request.from_address.trim().to_string(),
+            drop_dir: request.drop_dir.trim().to_string(),
+            organization_name: request.organization_name.trim().to_string(),
+            logo_url: request.logo_url.trim().to_string(),
+        };
+        smtp::validate(&settings)?;
+        
+        db.save_smtp_settings(&settings)?;
+        record_security_event(db, "smtp_settings_update", &admin.username, Some(&admin.id), "success", None)?;
+        
+        // Environment overrides still apply on top of what was just saved
+        let effective = load_smtp_settings(db)?;
+        let mailer = mailer::from_settings(&effective)?;
+        *app.mailer.write().map_err(|_| AppError::internal("Mailer lock failed"))? = mailer;
+        
+        Ok(smtp::masked(&effective))
+    }).await
+}
+
+#[tauri::command]
+async fn send_test_email(
+    state: State<'_, Arc<AppState>>,
+    to: Option<String>,
+    session_token: String,
+) -> AppResult<String> {
+    let (to, settings) = state.db.read(move |db| {
+        let admin = require_admin(db, &session_token)?;
+        Ok((to.map(|to| to.trim().to_string()).unwrap_or(admin.email), load_smtp_settings(db)?))
+    }).await?;
+    
+    let email = email_templates::render(EmailTemplate::Alert, &Branding::from_settings(&settings), &to, &[
+        ("title", "Test email"),
+        ("message", "If you received this, outgoing email is configured correctly."),
+    ]);
+    // Sent directly rather than queued, so the admin sees the result straight away,
+    // including what the server said
+    let mailer = current_mailer(&state)?;
+    mailer.send(&email).await.map_err(|e| match e {
+        MailError::Delivery(_) => AppError::validation(format!("Could not send the test email: {}", e)),
+        e => AppError::from(e),
+    })?;
+    Ok(format!("Test email sent to {}", to))
+}
+
+#[tauri::command]
+async fn get_email_outbox(
+    state: State<'_, Arc<AppState>>,
+    status: Option<String>,
+    session_token: String,
+) -> AppResult<Vec<OutboxEmail>> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.get_outbox_emails(status.as_deref())
+    }).await
+}
+
+#[tauri::command]
+async fn retry_outbox_email(
+    state: State<'_, Arc<AppState>>,
+    id: String,
+    session_token: String,
+) -> AppResult<String> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        require_admin(db, &session_token)?;
+        
+        if !db.retry_outbox_email(&id)? {
+            return Err(AppError::not_found("No failed email with that id that can be sent again"));
+        }
+        app.outbox.wake();
+        Ok("Email queued for another attempt".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn discard_outbox_email(
+    state: State<'_, Arc<AppState>>,
+    id: String,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        require_admin(db, &session_token)?;
+        
+        if !db.discard_outbox_email(&id)? {
+            return Err(AppError::not_found("No undelivered email with that id"));
+        }
+        Ok("Email discarded".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn get_stock_digest_subscriptions(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<Vec<StockDigestSubscription>> {
+    state.db.read(move |db| {
+        require_admin(db, &session_token)?;
+        db.get_stock_digest_subscriptions()
+    }).await
 }
 
+// Users manage their own subscription; admins can manage anyone's
+#[tauri::command]
+async fn set_stock_digest_subscription(
+    state: State<'_, Arc<AppState>>,
+    user_id: Option<String>,
+    subscribed: bool,
+    include_moderate: bool,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        let current_user = require_session(db, &session_token)?;
+        
+        let target_id = match user_id {
+            Some(user_id) if user_id != current_user.id => {
+                require_admin(db, &session_token)?;
+                db.get_user_by_id(&user_id)?
+                    .ok_or_else(|| AppError::not_found("User not found"))?;
+                user_id
+            }
+            _ => {
+                check_permission(&current_user, Module::Supplies, Action::View)?;
+                current_user.id
+            }
+        };
+        
+        if subscribed {
+            db.set_stock_digest_subscription(&target_id, include_moderate)
+        } else {
+            db.remove_stock_digest_subscription(&target_id)
+        }?;
+        
+        Ok("Subscription updated successfully".to_string())
+    }).await
+}
 
+#[tauri::command]
+async fn run_stock_digest(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<String> {
+    let app = Arc::clone(&state);
+    state.db.write(move |db| {
+        require_admin(db, &session_token)?;
+        let queued = stock_digest::run_now(db, &app.outbox)?;
+        Ok(format!("Queued {} digest email(s)", queued))
+    }).await
+}
 
 #[tauri::command]
-async fn get_version() -> Result<String, String> {
+async fn recalculate_stock_status(
+    state: State<'_, Arc<AppState>>,
+    session_token: String,
+) -> AppResult<String> {
+    state.db.write(move |db| {
+        authorize(db, &session_token, Module::Supplies, Action::Edit)?;
+        
+        db.recalculate_all_stock_status()?;
+        Ok("Stock status recalculated successfully".to_string())
+    }).await
+}
+
+#[tauri::command]
+async fn get_version() -> AppResult<String> {
     Ok("1.0.0".to_string())
 }
 
 #[tauri::command]
-async fn get_name() -> Result<String, String> {
+async fn get_name() -> AppResult<String> {
     Ok("OSSMS Desktop".to_string())
 }
 
-// Helper function to generate a random token
-fn generate_reset_token() -> String {
+// Helper function to generate an opaque session token
+fn generate_session_token() -> String {
     let mut rng = rand::thread_rng();
-    let token: String = (0..32)
+    let token: String = (0..48)
         .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
         .collect();
     token
 }
 
-// Helper function to send email
-async fn send_reset_email(email: &str, token: &str, username: &str) -> Result<(), String> {
-    let email_body = format!(
-        "Hello {},\n\nYou have requested a password reset for your OSSMS account.\n\nYour reset token is: {}\n\nThis token will expire in 1 hour.\n\nIf you did not request this reset, please ignore this email.\n\nBest regards,\nOSSMS Team",
-        username, token
-    );
+// Helper function to decide what a fully authenticated user may do with their session:
+// a pending forced password change comes first, then any required 2FA enrollment
+fn signed_in_scope(db: &Database, user: &User) -> AppResult<&'static str> {
+    if db.get_must_change_password(&user.id)? {
+        return Ok(SESSION_SCOPE_PASSWORD_CHANGE);
+    }
+    
+    let totp_state = db.get_totp_state(&user.id)?;
+    if !totp_state.enabled && db.role_requires_2fa(&user.role)? {
+        return Ok(SESSION_SCOPE_2FA_SETUP);
+    }
+    
+    Ok(SESSION_SCOPE_FULL)
+}
+
+fn session_lifetime(scope: &str) -> chrono::Duration {
+    match scope {
+        SESSION_SCOPE_FULL => chrono::Duration::hours(SESSION_LIFETIME_HOURS),
+        SESSION_SCOPE_2FA_PENDING => chrono::Duration::minutes(TWO_FACTOR_CHALLENGE_MINUTES),
+        _ => chrono::Duration::minutes(RESTRICTED_SESSION_MINUTES),
+    }
+}
 
-    let email_message = Message::builder()
-        .from("noreply@ossms.com".parse().unwrap())
-        .to(email.parse().unwrap())
-        .subject("OSSMS Password Reset")
-        .body(email_body)
-        .map_err(|e| format!("Failed to create email: {}", e))?;
+// Helper function to create a session and return its token and expiry
+fn issue_session(db: &Database, user_id: &str, scope: &str) -> AppResult<(String, String)> {
+    let token = generate_session_token();
+    let expires_at = (chrono::Utc::now() + session_lifetime(scope)).to_rfc3339();
+    db.create_session(user_id, &token, scope, &expires_at)?;
+    Ok((token, expires_at))
+}
 
-    // Use hardcoded credentials for easier setup
-    let creds = Credentials::new(SMTP_EMAIL.to_string(), SMTP_PASSWORD.to_string());
+// Helper function to move a restricted session on once its requirement is met
+fn upgrade_session(db: &Database, session_token: &str, user: &User) -> AppResult<()> {
+    let scope = signed_in_scope(db, user)?;
+    let expires_at = (chrono::Utc::now() + session_lifetime(scope)).to_rfc3339();
+    db.update_session_scope(session_token, scope, &expires_at)
+}
+
+// Helper function to resolve a session that may be in one of the given scopes
+fn require_session_scope(db: &Database, session_token: &str, scopes: &[&str]) -> AppResult<(User, String)> {
+    match db.get_session(session_token) {
+        Ok(Some((user, scope))) if scopes.contains(&scope.as_str()) => Ok((user, scope)),
+        Ok(_) => Err(AppError::forbidden("Session expired or invalid")),
+        Err(e) => Err(e),
+    }
+}
+
+// Helper function to check a TOTP or recovery code for a user with 2FA enabled.
+// Returns which method matched, if any.
+// Check a code for a signed-in user, e.g. before changing their two-factor settings.
+// Wrong codes count toward the same lockout as sign-in attempts.
+fn confirm_second_factor(db: &Database, lockout_policy: &LockoutPolicy, user: &User, code: &str) -> AppResult<()> {
+    if db.get_login_lockout(&user.username, Some(&user.id))?.is_some() {
+        record_security_event(db, "two_factor", &user.username, Some(&user.id), "failure", Some("locked_out"))?;
+        return Err(AppError::validation(INVALID_TWO_FACTOR_MESSAGE));
+    }
+    
+    if check_second_factor(db, user, code)?.is_some() {
+        db.clear_failed_logins(&user.username, Some(&user.id))?;
+        return Ok(());
+    }
+    
+    let failures = db.record_failed_login(&user.username, Some(&user.id))?;
+    if let Some(lockout) = lockout_policy.lockout_for(failures) {
+        let locked_until = (chrono::Utc::now() + lockout).to_rfc3339();
+        db.lock_login(&user.username, Some(&user.id), &locked_until)?;
+    }
+    record_security_event(db, "two_factor", &user.username, Some(&user.id), "failure", Some("bad_code"))?;
+    Err(AppError::validation(INVALID_TWO_FACTOR_MESSAGE))
+}
+fn check_second_factor(db: &Database, user: &User, code: &str) -> AppResult<Option<&'static str>> {
+    let totp_state = db.get_totp_state(&user.id)?;
+    let secret = match totp_state.secret {
+        Some(secret) if totp_state.enabled => secret,
+        _ => return Ok(None),
+    };
     
-    let mailer = SmtpTransport::relay("smtp.gmail.com")
-        .unwrap()
-        .credentials(creds)
-        .build();
+    if let Some(step) = totp::verify_code(&secret, &user.username, code, totp_state.last_step).map_err(AppError::internal)? {
+        db.set_totp_last_step(&user.id, step)?;
+        return Ok(Some("totp"));
+    }
+    
+    if db.consume_recovery_code(&user.id, &totp::hash_recovery_code(code))? {
+        return Ok(Some("recovery_code"));
+    }
+    
+    Ok(None)
+}
+
+// Helper function to replace a user's recovery codes, returning the plaintext codes once
+fn new_recovery_codes(db: &Database, user_id: &str) -> AppResult<Vec<String>> {
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+    db.replace_recovery_codes(user_id, &hashes)?;
+    Ok(codes)
+}
+
+// Helper function to check a new password against the policy and the user's recent passwords
+async fn check_new_password(state: &AppState, user_id: &str, password: &str) -> AppResult<()> {
+    state.password_policy.validate(password)?;
+    
+    let depth = state.password_policy.history_depth;
+    if depth > 0 {
+        let user_id = user_id.to_string();
+        let hashes = state.db.read(move |db| db.recent_password_hashes(&user_id, depth)).await?;
+        if password_matches(password.to_string(), hashes).await? {
+            return Err(AppError::validation(format!("Password must not match any of your last {} passwords", depth)));
+        }
+    }
+    
+    Ok(())
+}
+
+// bcrypt is slow on purpose, so it runs on a blocking thread of its own instead of in a
+// pool closure, where it would hold up every other write meanwhile
+async fn hash_password(password: String) -> AppResult<String> {
+    tauri::async_runtime::spawn_blocking(move || bcrypt::hash(password, bcrypt::DEFAULT_COST))
+        .await
+        .map_err(|_| AppError::internal("Password task failed"))?
+        .map_err(AppError::from)
+}
+
+// Whether the password matches any of the hashes
+async fn password_matches(password: String, hashes: Vec<String>) -> AppResult<bool> {
+    tauri::async_runtime::spawn_blocking(move || -> AppResult<bool> {
+        for password_hash in &hashes {
+            if bcrypt::verify(&password, password_hash)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })
+    .await
+    .map_err(|_| AppError::internal("Password task failed"))?
+}
+
+// Helper function to resolve the acting user from a session token
+fn require_session(db: &Database, session_token: &str) -> AppResult<User> {
+    match db.get_session_user(session_token) {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(AppError::forbidden("Session expired or invalid")),
+        Err(e) => Err(e),
+    }
+}
+
+// Helper function to resolve the acting user and check a permission before touching the database
+fn authorize(db: &Database, session_token: &str, module: Module, action: Action) -> AppResult<User> {
+    let user = require_session(db, session_token)?;
+    check_permission(&user, module, action)?;
+    Ok(user)
+}
+
+// Helper function for commands reserved to administrators
+fn require_admin(db: &Database, session_token: &str) -> AppResult<User> {
+    let user = require_session(db, session_token)?;
+    if user.role != "admin" {
+        return Err(AppError::forbidden("Forbidden: administrator access required"));
+    }
+    Ok(user)
+}
 
-    mailer.send(&email_message)
-        .map_err(|e| format!("Failed to send email: {}", e))?;
+// Helper function to append to the security event log
+fn record_security_event(db: &Database, event_type: &str, username: &str, user_id: Option<&str>, outcome: &str, reason: Option<&str>) -> AppResult<()> {
+    db.record_security_event(event_type, username, user_id, outcome, reason)
+}
+
+// Same, for commands that aren't already holding the writer
+async fn log_security_event(state: &AppState, event_type: &'static str, username: &str, user_id: Option<&str>, outcome: &'static str, reason: Option<&'static str>) -> AppResult<()> {
+    let (username, user_id) = (username.to_string(), user_id.map(str::to_string));
+    state.db.write(move |db| record_security_event(db, event_type, &username, user_id.as_deref(), outcome, reason)).await
+}
 
+// Helper function to read the SMTP settings in effect, including environment overrides
+fn load_smtp_settings(db: &Database) -> AppResult<SmtpSettings> {
+    let stored = db.get_smtp_settings()?;
+    Ok(smtp::effective_settings(stored))
+}
+
+// Helper function to get the mailer in use
+fn current_mailer(state: &AppState) -> AppResult<Arc<dyn Mailer>> {
+    state.mailer.read()
+        .map(|mailer| mailer.clone())
+        .map_err(|_| AppError::internal("Mailer lock failed"))
+}
+
+// Helper function to validate and normalize an account's email address.
+// `user_id` is the account being edited, which may keep its own address.
+fn check_email(db: &Database, email: &str, user_id: Option<&str>) -> AppResult<String> {
+    let email = mailer::normalize_address(email)?;
+    if db.email_in_use(&email, user_id)? {
+        return Err(AppError::conflict("Email address is already in use"));
+    }
+    Ok(email)
+}
+
+// Helper function to render a template with the organization's branding and queue it
+fn queue_email(state: &AppState, db: &Database, template: EmailTemplate, to: &str, vars: &[(&str, &str)], expires_at: Option<&str>) -> AppResult<()> {
+    let settings = load_smtp_settings(db)?;
+    let email = email_templates::render(template, &Branding::from_settings(&settings), to, vars);
+    state.outbox.enqueue(db, &email, expires_at)?;
     Ok(())
 }
 
 fn main() {
-    // Initialize database with optimizations
-    let database = match Database::new() {
+    // Open the writer and the reader connections
+    let database = match DbPool::open() {
         Ok(db) => db,
         Err(e) => {
             eprintln!("Failed to initialize database: {}", e);
@@ -623,26 +1527,93 @@ fn main() {
         }
     };
     
+    let mail_settings = match tauri::async_runtime::block_on(database.read(load_smtp_settings)) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Failed to load mail settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mailer = match mailer::from_settings(&mail_settings) {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            eprintln!("Invalid mail settings: {}", e);
+            std::process::exit(1);
+        }
+    };
+    
     let app_state = AppState {
-        db: Mutex::new(database),
+        db: database,
+        lockout_policy: LockoutPolicy::from_env(),
+        password_policy: PasswordPolicy::from_env(),
+        reset_throttle_policy: ResetThrottlePolicy::from_env(),
+        mailer: RwLock::new(mailer),
+        outbox: Arc::new(Outbox::new(OutboxPolicy::from_env())),
+        stock_digest_policy: StockDigestPolicy::from_env(),
     };
 
     tauri::Builder::default()
-        .manage(app_state)
+        .manage(Arc::new(app_state))
+        .setup(|app| {
+            // Deliver queued emails in the background
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let state = handle.state::<Arc<AppState>>();
+                state.outbox.run(&state.db, &state.mailer).await;
+            });
+            
+            // Send the low-stock digest on its schedule
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let state = handle.state::<Arc<AppState>>();
+                stock_digest::run(&state.stock_digest_policy, &state.db, &state.outbox).await;
+            });
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
+            setup_status,
+            complete_setup,
+            load_demo_data,
             login,
+            verify_two_factor,
+            logout,
+            revoke_user_sessions,
             get_users,
             create_user,
             update_user,
             delete_user,
-    
+            change_password,
+            admin_reset_password,
+            unlock_user,
+            get_security_events,
+            get_audit_log,
+            check_database_integrity,
+            begin_totp_enrollment,
+            confirm_totp_enrollment,
+            regenerate_recovery_codes,
+            disable_totp,
+            reset_user_two_factor,
+            get_role_policies,
+            set_role_two_factor_requirement,
+            get_smtp_settings,
+            update_smtp_settings,
+            send_test_email,
+            get_email_outbox,
+            retry_outbox_email,
+            discard_outbox_email,
             get_supplies,
             create_supply,
             update_supply,
+            stock_in,
+            stock_out,
+            adjust_stock,
             get_supply_histories,
             delete_supply,
             delete_supply_history,
             recalculate_stock_status,
+            get_stock_digest_subscriptions,
+            set_stock_digest_subscription,
+            run_stock_digest,
             forgot_password,
             reset_password,
             get_version,
@@ -0,0 +1,926 @@
+use rusqlite::{Connection, Result};
+use std::fmt;
+
+// Schema migrations, applied in order by Database::new.
+//
+// The schema version is kept in SQLite's `PRAGMA user_version`. Each migration runs
+// in its own transaction together with the version bump, so a failed migration leaves
+// the database exactly as it was. To change the schema, append a migration with the
+// next version number; never edit one that has shipped.
+pub struct Migration {
+    pub version: i32,
+    pub description: &'static str,
+    pub up: fn(&Connection) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "initial schema", up: initial_schema },
+    Migration { version: 2, description: "audit log", up: audit_log },
+    Migration { version: 3, description: "history name snapshots", up: history_name_snapshots },
+    Migration { version: 4, description: "foreign keys", up: foreign_keys },
+    Migration { version: 5, description: "supply versions", up: supply_versions },
+    Migration { version: 6, description: "stock movement details", up: stock_movement_details },
+    Migration { version: 7, description: "unique email check", up: unique_email_check },
+    Migration { version: 8, description: "outbox expiry", up: outbox_expiry },
+    Migration { version: 9, description: "hashed session tokens", up: hashed_session_tokens },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    // The database was written by a newer version of the app
+    NewerSchema { found: i32, supported: i32 },
+    Failed { version: i32, description: &'static str, source: rusqlite::Error },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(e) => write!(f, "{}", e),
+            MigrationError::NewerSchema { found, supported } => write!(
+                f,
+                "Database schema version {} is newer than this version of the app supports ({}), please update the app",
+                found, supported
+            ),
+            MigrationError::Failed { version, description, source } => {
+                write!(f, "Migration {} ({}) failed: {}", version, description, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(e)
+    }
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Bring the database up to the latest version. Returns the version it ended at.
+pub fn migrate(conn: &mut Connection) -> std::result::Result<i32, MigrationError> {
+    apply(conn, MIGRATIONS)
+}
+
+fn apply(conn: &mut Connection, migrations: &[Migration]) -> std::result::Result<i32, MigrationError> {
+    let supported = migrations.last().map(|m| m.version).unwrap_or(0);
+    let current = schema_version(conn)?;
+    if current > supported {
+        return Err(MigrationError::NewerSchema { found: current, supported });
+    }
+
+    let mut version = current;
+    for migration in migrations.iter().filter(|m| m.version > current) {
+        let failed = |source| MigrationError::Failed {
+            version: migration.version,
+            description: migration.description,
+            source,
+        };
+
+        let tx = conn.transaction().map_err(failed)?;
+        (migration.up)(&tx).map_err(failed)?;
+        // PRAGMA doesn't take bound parameters; the version is a plain integer
+        tx.execute_batch(&format!("PRAGMA user_version = {}", migration.version))
+            .map_err(failed)?;
+        tx.commit().map_err(failed)?;
+        version = migration.version;
+    }
+
+    Ok(version)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in columns {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Databases from before versioned migrations were upgraded by adding columns in place,
+// so a table may be missing any of its later columns
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+// Version 1: the schema as it stood when migrations were introduced. Also brings
+// unversioned databases from older releases up to that point.
+fn initial_schema(conn: &Connection) -> Result<()> {
+    // Users table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            username TEXT UNIQUE NOT NULL,
+            password TEXT NOT NULL,
+            firstname TEXT NOT NULL,
+            lastname TEXT NOT NULL,
+            email TEXT UNIQUE NOT NULL,
+            role TEXT NOT NULL DEFAULT 'user',
+            permissions TEXT,
+            failed_login_attempts INTEGER NOT NULL DEFAULT 0,
+            locked_until TEXT,
+            totp_secret TEXT,
+            totp_enabled BOOLEAN NOT NULL DEFAULT 0,
+            totp_last_step INTEGER,
+            must_change_password BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Emails are unique regardless of case. Normalize existing rows, then enforce it;
+    // rows that already clash are left alone and the index is skipped, the commands still check.
+    conn.execute("UPDATE OR IGNORE users SET email = lower(trim(email)) WHERE email <> lower(trim(email))", [])?;
+    let clashing_emails: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT 1 FROM users GROUP BY email COLLATE NOCASE HAVING COUNT(*) > 1)",
+        [],
+        |row| row.get(0),
+    )?;
+    if clashing_emails == 0 {
+        conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email_nocase ON users (email COLLATE NOCASE)", [])?;
+    } else {
+        eprintln!("Skipping unique email index: {} email address(es) are shared by several users", clashing_emails);
+    }
+
+    // Add login lockout columns if they don't exist (for existing databases)
+    add_column_if_missing(conn, "users", "failed_login_attempts", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "locked_until", "TEXT")?;
+    // Add two-factor columns if they don't exist (for existing databases)
+    add_column_if_missing(conn, "users", "totp_secret", "TEXT")?;
+    add_column_if_missing(conn, "users", "totp_enabled", "BOOLEAN NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "totp_last_step", "INTEGER")?;
+    // Add forced password change flag if it doesn't exist (for existing databases)
+    add_column_if_missing(conn, "users", "must_change_password", "BOOLEAN NOT NULL DEFAULT 0")?;
+
+    // Supplies table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS supplies (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            description TEXT,
+            category TEXT NOT NULL,
+            subcategory TEXT,
+            variation TEXT,
+            brand TEXT,
+            quantity INTEGER NOT NULL DEFAULT 0,
+            unit TEXT NOT NULL,
+            min_quantity INTEGER NOT NULL DEFAULT 0,
+            status TEXT NOT NULL DEFAULT 'active',
+            location TEXT,
+            supplier TEXT,
+            supplier_name TEXT,
+            supplier_contact TEXT,
+            supplier_notes TEXT,
+            cost REAL,
+            pieces_per_bulk INTEGER DEFAULT 12,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Add subcategory column if it doesn't exist (for existing databases)
+    add_column_if_missing(conn, "supplies", "subcategory", "TEXT")?;
+    // Add pieces_per_bulk column if it doesn't exist (for existing databases)
+    add_column_if_missing(conn, "supplies", "pieces_per_bulk", "INTEGER DEFAULT 12")?;
+    // Add separate supplier columns if they don't exist (for existing databases)
+    add_column_if_missing(conn, "supplies", "supplier_name", "TEXT")?;
+    add_column_if_missing(conn, "supplies", "supplier_contact", "TEXT")?;
+    add_column_if_missing(conn, "supplies", "supplier_notes", "TEXT")?;
+    // Add variation and brand columns if they don't exist (for existing databases)
+    add_column_if_missing(conn, "supplies", "variation", "TEXT")?;
+    add_column_if_missing(conn, "supplies", "brand", "TEXT")?;
+
+    // Supply history table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS supply_histories (
+            id TEXT PRIMARY KEY,
+            supply_id TEXT NOT NULL,
+            action TEXT NOT NULL,
+            quantity INTEGER NOT NULL,
+            previous_quantity INTEGER NOT NULL,
+            new_quantity INTEGER NOT NULL,
+            notes TEXT,
+            user_id TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Password reset tokens table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS password_reset_tokens (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            token TEXT UNIQUE NOT NULL,
+            expires_at TEXT NOT NULL,
+            used BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    // Tokens used to be stored in plain text; those can never match a hash, so drop them
+    conn.execute("DELETE FROM password_reset_tokens WHERE length(token) <> 64", [])?;
+
+    // Previous password hashes, used to prevent password reuse
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS password_history (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            password_hash TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Failed login counters per attempted username (including unknown usernames)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS login_throttles (
+            username TEXT PRIMARY KEY,
+            failed_attempts INTEGER NOT NULL DEFAULT 0,
+            locked_until TEXT,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Security events table (logins, password resets, reset requests)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS security_events (
+            id TEXT PRIMARY KEY,
+            event_type TEXT NOT NULL,
+            username TEXT NOT NULL,
+            user_id TEXT,
+            outcome TEXT NOT NULL,
+            reason TEXT,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_security_events_created_at ON security_events (created_at)",
+        [],
+    )?;
+
+    // Login sessions table
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS sessions (
+            token TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            scope TEXT NOT NULL DEFAULT 'full',
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Add scope column if it doesn't exist (for existing databases)
+    add_column_if_missing(conn, "sessions", "scope", "TEXT NOT NULL DEFAULT 'full'")?;
+
+    // Single-use two-factor recovery codes (SHA-256 hashes)
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS recovery_codes (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            used_at TEXT,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Per-role security policies
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS role_policies (
+            role TEXT PRIMARY KEY,
+            require_2fa BOOLEAN NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Outgoing mail settings, a single row edited by admins
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS smtp_settings (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            host TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            tls_mode TEXT NOT NULL,
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            from_address TEXT NOT NULL,
+            transport TEXT NOT NULL DEFAULT 'smtp',
+            drop_dir TEXT NOT NULL DEFAULT '',
+            organization_name TEXT NOT NULL DEFAULT 'OSSMS',
+            logo_url TEXT NOT NULL DEFAULT '',
+            updated_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    // Emails waiting for delivery, plus a record of sent and failed ones
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS email_outbox (
+            id TEXT PRIMARY KEY,
+            to_address TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            body TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            next_attempt_at TEXT,
+            created_at TEXT NOT NULL,
+            sent_at TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_email_outbox_status ON email_outbox (status, next_attempt_at)",
+        [],
+    )?;
+
+    // Users who get the low-stock digest
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS stock_digest_subscriptions (
+            user_id TEXT PRIMARY KEY,
+            include_moderate BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Items already reported to each subscriber, and at which status
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS stock_digest_reports (
+            user_id TEXT NOT NULL,
+            supply_id TEXT NOT NULL,
+            status TEXT NOT NULL,
+            reported_at TEXT NOT NULL,
+            PRIMARY KEY (user_id, supply_id)
+        )",
+        [],
+    )?;
+
+    // Last run of each scheduled job
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS job_runs (
+            job TEXT PRIMARY KEY,
+            last_run_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Add mail transport and HTML body columns if they don't exist (for existing databases)
+    add_column_if_missing(conn, "smtp_settings", "transport", "TEXT NOT NULL DEFAULT 'smtp'")?;
+    add_column_if_missing(conn, "smtp_settings", "drop_dir", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "smtp_settings", "organization_name", "TEXT NOT NULL DEFAULT 'OSSMS'")?;
+    add_column_if_missing(conn, "smtp_settings", "logo_url", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(conn, "email_outbox", "html_body", "TEXT")?;
+
+    Ok(())
+}
+
+// Version 2: a separate audit log for user and history administration. These events
+// used to be written to supply_histories with supply_id 'SYSTEM', where the join on
+// supplies hid them from every view.
+fn audit_log(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE audit_log (
+            id TEXT PRIMARY KEY,
+            actor_id TEXT,
+            actor_username TEXT,
+            action TEXT NOT NULL,
+            target_type TEXT NOT NULL,
+            target_id TEXT,
+            details TEXT,
+            created_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX idx_audit_log_created_at ON audit_log (created_at)", [])?;
+    conn.execute("CREATE INDEX idx_audit_log_target ON audit_log (target_type, target_id)", [])?;
+
+    // The old rows only have a free-text note and don't record what they were about
+    conn.execute(
+        "INSERT INTO audit_log (id, actor_id, actor_username, action, target_type, target_id, details, created_at)
+         SELECT sh.id, sh.user_id, u.username, sh.action,
+                CASE
+                    WHEN substr(sh.action, 1, 5) = 'USER_' THEN 'user'
+                    WHEN sh.action = 'HISTORY_DELETED' THEN 'supply_history'
+                    ELSE 'system'
+                END,
+                NULL, json_object('notes', sh.notes), sh.created_at
+         FROM supply_histories sh
+         LEFT JOIN users u ON u.id = sh.user_id
+         WHERE sh.supply_id = 'SYSTEM'",
+        [],
+    )?;
+    conn.execute("DELETE FROM supply_histories WHERE supply_id = 'SYSTEM'", [])?;
+
+    Ok(())
+}
+
+// Version 3: supply and user names copied onto each history row, so the movement
+// ledger still reads after the supply or user is deleted
+fn history_name_snapshots(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE supply_histories ADD COLUMN supply_name TEXT", [])?;
+    conn.execute("ALTER TABLE supply_histories ADD COLUMN user_name TEXT", [])?;
+
+    // Rows whose supply or user is already gone can't be recovered and keep NULL
+    conn.execute(
+        "UPDATE supply_histories SET
+            supply_name = (SELECT name FROM supplies WHERE supplies.id = supply_histories.supply_id),
+            user_name = (
+                SELECT CASE WHEN firstname IS NOT NULL AND lastname IS NOT NULL THEN firstname || ' ' || lastname ELSE username END
+                FROM users WHERE users.id = supply_histories.user_id
+            )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+// Child columns that get a foreign key in version 4, as (table, column, parent table)
+const FOREIGN_KEYS: &[(&str, &str, &str)] = &[
+    ("supply_histories", "supply_id", "supplies"),
+    ("supply_histories", "user_id", "users"),
+    ("password_reset_tokens", "user_id", "users"),
+    ("password_history", "user_id", "users"),
+    ("sessions", "user_id", "users"),
+    ("recovery_codes", "user_id", "users"),
+    ("stock_digest_subscriptions", "user_id", "users"),
+    ("stock_digest_reports", "user_id", "users"),
+    ("stock_digest_reports", "supply_id", "supplies"),
+];
+
+// SQLite can't add a constraint to an existing table, so the table is rebuilt:
+// `create` makes `<table>_new`, `copy` fills it, then the old table is replaced
+fn rebuild_table(conn: &Connection, table: &str, create: &str, copy: &str) -> Result<()> {
+    conn.execute(create, [])?;
+    conn.execute(copy, [])?;
+    conn.execute(&format!("DROP TABLE {}", table), [])?;
+    conn.execute(&format!("ALTER TABLE {}_new RENAME TO {}", table, table), [])?;
+    Ok(())
+}
+
+// Version 4: foreign keys on every column that points at a user or a supply.
+// History keeps its rows when the supply or user goes (the names are snapshotted),
+// everything else belongs to the user or supply and goes with it.
+//
+// Rows that already point at a missing user or supply can't be copied into the new
+// tables: history references are cleared and the other rows are dropped. What was
+// cleaned up is recorded in the audit log.
+fn foreign_keys(conn: &Connection) -> Result<()> {
+    let mut orphans = serde_json::Map::new();
+    for (table, column, parent) in FOREIGN_KEYS {
+        let count: i64 = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {table} WHERE {column} IS NOT NULL AND {column} NOT IN (SELECT id FROM {parent})",
+                table = table,
+                column = column,
+                parent = parent
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        if count > 0 {
+            orphans.insert(format!("{}.{}", table, column), count.into());
+        }
+    }
+
+    rebuild_table(
+        conn,
+        "supply_histories",
+        "CREATE TABLE supply_histories_new (
+            id TEXT PRIMARY KEY,
+            supply_id TEXT REFERENCES supplies (id) ON DELETE SET NULL,
+            action TEXT NOT NULL,
+            quantity INTEGER NOT NULL,
+            previous_quantity INTEGER NOT NULL,
+            new_quantity INTEGER NOT NULL,
+            notes TEXT,
+            user_id TEXT REFERENCES users (id) ON DELETE SET NULL,
+            created_at TEXT NOT NULL,
+            supply_name TEXT,
+            user_name TEXT
+        )",
+        "INSERT INTO supply_histories_new (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at, supply_name, user_name)
+         SELECT id,
+                CASE WHEN supply_id IN (SELECT id FROM supplies) THEN supply_id END,
+                action, quantity, previous_quantity, new_quantity, notes,
+                CASE WHEN user_id IN (SELECT id FROM users) THEN user_id END,
+                created_at, supply_name, user_name
+         FROM supply_histories",
+    )?;
+    conn.execute("CREATE INDEX idx_supply_histories_supply_id ON supply_histories (supply_id)", [])?;
+    conn.execute("CREATE INDEX idx_supply_histories_user_id ON supply_histories (user_id)", [])?;
+
+    rebuild_table(
+        conn,
+        "password_reset_tokens",
+        "CREATE TABLE password_reset_tokens_new (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            token TEXT UNIQUE NOT NULL,
+            expires_at TEXT NOT NULL,
+            used BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL
+        )",
+        "INSERT INTO password_reset_tokens_new (id, user_id, token, expires_at, used, created_at)
+         SELECT id, user_id, token, expires_at, used, created_at
+         FROM password_reset_tokens WHERE user_id IN (SELECT id FROM users)",
+    )?;
+
+    rebuild_table(
+        conn,
+        "password_history",
+        "CREATE TABLE password_history_new (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            password_hash TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        "INSERT INTO password_history_new (id, user_id, password_hash, created_at)
+         SELECT id, user_id, password_hash, created_at
+         FROM password_history WHERE user_id IN (SELECT id FROM users)",
+    )?;
+
+    rebuild_table(
+        conn,
+        "sessions",
+        "CREATE TABLE sessions_new (
+            token TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            scope TEXT NOT NULL DEFAULT 'full',
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+        "INSERT INTO sessions_new (token, user_id, scope, expires_at, created_at)
+         SELECT token, user_id, scope, expires_at, created_at
+         FROM sessions WHERE user_id IN (SELECT id FROM users)",
+    )?;
+
+    rebuild_table(
+        conn,
+        "recovery_codes",
+        "CREATE TABLE recovery_codes_new (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            code_hash TEXT NOT NULL,
+            used_at TEXT,
+            created_at TEXT NOT NULL
+        )",
+        "INSERT INTO recovery_codes_new (id, user_id, code_hash, used_at, created_at)
+         SELECT id, user_id, code_hash, used_at, created_at
+         FROM recovery_codes WHERE user_id IN (SELECT id FROM users)",
+    )?;
+
+    rebuild_table(
+        conn,
+        "stock_digest_subscriptions",
+        "CREATE TABLE stock_digest_subscriptions_new (
+            user_id TEXT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+            include_moderate BOOLEAN NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL
+        )",
+        "INSERT INTO stock_digest_subscriptions_new (user_id, include_moderate, created_at)
+         SELECT user_id, include_moderate, created_at
+         FROM stock_digest_subscriptions WHERE user_id IN (SELECT id FROM users)",
+    )?;
+
+    rebuild_table(
+        conn,
+        "stock_digest_reports",
+        "CREATE TABLE stock_digest_reports_new (
+            user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            supply_id TEXT NOT NULL REFERENCES supplies (id) ON DELETE CASCADE,
+            status TEXT NOT NULL,
+            reported_at TEXT NOT NULL,
+            PRIMARY KEY (user_id, supply_id)
+        )",
+        "INSERT INTO stock_digest_reports_new (user_id, supply_id, status, reported_at)
+         SELECT user_id, supply_id, status, reported_at
+         FROM stock_digest_reports
+         WHERE user_id IN (SELECT id FROM users) AND supply_id IN (SELECT id FROM supplies)",
+    )?;
+
+    if !orphans.is_empty() {
+        conn.execute(
+            "INSERT INTO audit_log (id, action, target_type, details, created_at) VALUES (?1, 'ORPHANS_CLEANED', 'system', ?2, ?3)",
+            rusqlite::params![
+                uuid::Uuid::new_v4().to_string(),
+                serde_json::Value::Object(orphans).to_string(),
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Version 5: a version number on each supply, bumped on every edit, so an edit made
+// against an outdated copy of the row can be refused
+fn supply_versions(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE supplies ADD COLUMN version INTEGER NOT NULL DEFAULT 1", [])?;
+    Ok(())
+}
+
+// Version 6: reason code, reference number and department for stock movements
+// recorded through stock_in, stock_out and adjust_stock
+fn stock_movement_details(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE supply_histories ADD COLUMN reason_code TEXT", [])?;
+    conn.execute("ALTER TABLE supply_histories ADD COLUMN reference_number TEXT", [])?;
+    conn.execute("ALTER TABLE supply_histories ADD COLUMN department TEXT", [])?;
+    Ok(())
+}
+
+// Version 7: the initial schema skips the unique email index when users share an
+// address, and only says so on stderr, which a desktop app doesn't show. Create the
+// index if they no longer do, otherwise note it in the audit log, where administrators
+// can see it.
+fn unique_email_check(conn: &Connection) -> Result<()> {
+    let clashing_emails: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM (SELECT 1 FROM users GROUP BY email COLLATE NOCASE HAVING COUNT(*) > 1)",
+        [],
+        |row| row.get(0),
+    )?;
+    if clashing_emails == 0 {
+        conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email_nocase ON users (email COLLATE NOCASE)", [])?;
+    } else {
+        conn.execute(
+            "INSERT INTO audit_log (id, action, target_type, details, created_at) VALUES (?1, 'EMAIL_INDEX_SKIPPED', 'system', ?2, ?3)",
+            rusqlite::params![
+                uuid::Uuid::new_v4().to_string(),
+                serde_json::json!({ "shared_emails": clashing_emails }).to_string(),
+                chrono::Utc::now().to_rfc3339()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+// Version 8: emails carrying a secret, like a password reset link, expire with it and
+// are dropped from the outbox instead of being kept or sent late
+fn outbox_expiry(conn: &Connection) -> Result<()> {
+    conn.execute("ALTER TABLE email_outbox ADD COLUMN expires_at TEXT", [])?;
+    Ok(())
+}
+
+// Version 9: session tokens are stored as SHA-256 hashes, like reset tokens, so a copy
+// of the database can't be used to sign in. Existing sessions are hashed in place.
+fn hashed_session_tokens(conn: &Connection) -> Result<()> {
+    let tokens = conn
+        .prepare("SELECT token FROM sessions")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    for token in tokens {
+        conn.execute(
+            "UPDATE sessions SET token = ?1 WHERE token = ?2",
+            rusqlite::params![crate::reset_tokens::hash_token(&token), token],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_exists(conn: &Connection, table: &str) -> bool {
+        conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap()
+            == 1
+    }
+
+    fn create_probe(conn: &Connection) -> Result<()> {
+        conn.execute("CREATE TABLE probe (id INTEGER PRIMARY KEY)", [])?;
+        Ok(())
+    }
+
+    fn broken(conn: &Connection) -> Result<()> {
+        conn.execute("CREATE TABLE half_done (id INTEGER PRIMARY KEY)", [])?;
+        conn.execute("INSERT INTO no_such_table VALUES (1)", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn versions_are_consecutive() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i32 + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn fresh_database_gets_latest_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+
+        assert_eq!(migrate(&mut conn).unwrap(), latest);
+        assert_eq!(schema_version(&conn).unwrap(), latest);
+        for table in ["users", "supplies", "supply_histories", "sessions", "email_outbox", "job_runs"] {
+            assert!(table_exists(&conn, table), "missing table {}", table);
+        }
+
+        // Running again is a no-op
+        assert_eq!(migrate(&mut conn).unwrap(), latest);
+    }
+
+    #[test]
+    fn unversioned_database_is_upgraded_in_place() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                id TEXT PRIMARY KEY,
+                username TEXT UNIQUE NOT NULL,
+                password TEXT NOT NULL,
+                firstname TEXT NOT NULL,
+                lastname TEXT NOT NULL,
+                email TEXT UNIQUE NOT NULL,
+                role TEXT NOT NULL DEFAULT 'user',
+                permissions TEXT,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            INSERT INTO users VALUES ('u1', 'ann', 'x', 'Ann', 'Lee', ' Ann@Example.com ', 'admin', NULL, 't', 't');
+            CREATE TABLE supplies (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                description TEXT,
+                category TEXT NOT NULL,
+                quantity INTEGER NOT NULL DEFAULT 0,
+                unit TEXT NOT NULL,
+                min_quantity INTEGER NOT NULL DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'active',
+                location TEXT,
+                supplier TEXT,
+                cost REAL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        for (table, column) in [("users", "must_change_password"), ("users", "totp_enabled"), ("supplies", "brand"), ("supplies", "pieces_per_bulk")] {
+            assert!(has_column(&conn, table, column).unwrap(), "missing {}.{}", table, column);
+        }
+        let email: String = conn.query_row("SELECT email FROM users WHERE id = 'u1'", [], |row| row.get(0)).unwrap();
+        assert_eq!(email, "ann@example.com");
+    }
+
+    #[test]
+    fn system_history_rows_move_to_audit_log() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply(&mut conn, &MIGRATIONS[..1]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password, firstname, lastname, email, role, created_at, updated_at)
+                 VALUES ('admin1', 'admin', 'x', 'Ada', 'Min', 'admin@example.com', 'admin', 't', 't');
+             INSERT INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, notes, user_id, created_at)
+                 VALUES ('h1', 'SYSTEM', 'USER_CREATED', 0, 0, 0, 'User ''bob'' created by admin', 'admin1', 't1'),
+                        ('h2', 'SYSTEM', 'HISTORY_DELETED', 0, 0, 0, 'History record deleted', 'admin1', 't2'),
+                        ('h3', 'supply1', 'ADD', 5, 0, 5, NULL, 'admin1', 't3');",
+        )
+        .unwrap();
+
+        apply(&mut conn, &MIGRATIONS[..2]).unwrap();
+
+        let remaining: Vec<String> = conn
+            .prepare("SELECT id FROM supply_histories")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(remaining, vec!["h3".to_string()]);
+
+        let moved: Vec<(String, String, Option<String>, String)> = conn
+            .prepare("SELECT id, target_type, actor_username, json_extract(details, '$.notes') FROM audit_log ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(moved[0], ("h1".to_string(), "user".to_string(), Some("admin".to_string()), "User 'bob' created by admin".to_string()));
+        assert_eq!(moved[1].1, "supply_history");
+    }
+
+    #[test]
+    fn foreign_keys_clean_up_orphans_and_apply_on_delete() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA foreign_keys = ON").unwrap();
+        apply(&mut conn, &MIGRATIONS[..3]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password, firstname, lastname, email, role, created_at, updated_at)
+                 VALUES ('u1', 'ann', 'x', 'Ann', 'Lee', 'ann@example.com', 'staff', 't', 't');
+             INSERT INTO supplies (id, name, category, quantity, unit, min_quantity, created_at, updated_at)
+                 VALUES ('s1', 'Paper', 'Office', 5, 'ream', 1, 't', 't');
+             INSERT INTO supply_histories (id, supply_id, action, quantity, previous_quantity, new_quantity, user_id, created_at, supply_name, user_name)
+                 VALUES ('h1', 's1', 'ADD', 5, 0, 5, 'u1', 't', 'Paper', 'Ann Lee'),
+                        ('h2', 'gone', 'ADD', 1, 0, 1, 'u1', 't', 'Pens', 'Ann Lee');
+             INSERT INTO sessions (token, user_id, expires_at, created_at)
+                 VALUES ('live', 'u1', 't', 't'), ('stale', 'nobody', 't', 't');",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        // The orphaned history row is kept without its supply, the orphaned session is gone
+        let supply_id: Option<String> = conn.query_row("SELECT supply_id FROM supply_histories WHERE id = 'h2'", [], |row| row.get(0)).unwrap();
+        assert_eq!(supply_id, None);
+        let sessions: i64 = conn.query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0)).unwrap();
+        assert_eq!(sessions, 1);
+        let cleaned: String = conn
+            .query_row("SELECT details FROM audit_log WHERE action = 'ORPHANS_CLEANED'", [], |row| row.get(0))
+            .unwrap();
+        let cleaned: serde_json::Value = serde_json::from_str(&cleaned).unwrap();
+        assert_eq!(cleaned["supply_histories.supply_id"], 1);
+        assert_eq!(cleaned["sessions.user_id"], 1);
+
+        // From now on deletes follow the ON DELETE policies
+        conn.execute("DELETE FROM supplies WHERE id = 's1'", []).unwrap();
+        conn.execute("DELETE FROM users WHERE id = 'u1'", []).unwrap();
+        let (supply_id, user_id): (Option<String>, Option<String>) = conn
+            .query_row("SELECT supply_id, user_id FROM supply_histories WHERE id = 'h1'", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!((supply_id, user_id), (None, None));
+        let sessions: i64 = conn.query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0)).unwrap();
+        assert_eq!(sessions, 0);
+
+        // New orphans are refused
+        assert!(conn
+            .execute("INSERT INTO sessions (token, user_id, expires_at, created_at) VALUES ('x', 'nobody', 't', 't')", [])
+            .is_err());
+    }
+
+    #[test]
+    fn skipped_email_index_is_noted_in_the_audit_log() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply(&mut conn, &MIGRATIONS[..6]).unwrap();
+        // As left behind by the initial schema when users already shared an address
+        conn.execute_batch(
+            "DROP INDEX idx_users_email_nocase;
+             INSERT INTO users (id, username, password, firstname, lastname, email, role, created_at, updated_at)
+                 VALUES ('u1', 'ann', 'x', 'Ann', 'Lee', 'ann@example.com', 'staff', 't', 't'),
+                        ('u2', 'ann2', 'x', 'Ann', 'Lee', 'ANN@example.com', 'staff', 't', 't');",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let details: String = conn
+            .query_row("SELECT details FROM audit_log WHERE action = 'EMAIL_INDEX_SKIPPED'", [], |row| row.get(0))
+            .unwrap();
+        let details: serde_json::Value = serde_json::from_str(&details).unwrap();
+        assert_eq!(details["shared_emails"], 1);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", latest + 1)).unwrap();
+
+        match migrate(&mut conn) {
+            Err(MigrationError::NewerSchema { found, supported }) => {
+                assert_eq!(found, latest + 1);
+                assert_eq!(supported, latest);
+            }
+            other => panic!("expected NewerSchema, got {:?}", other),
+        }
+        assert!(!table_exists(&conn, "users"));
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration { version: 1, description: "probe", up: create_probe },
+            Migration { version: 2, description: "broken", up: broken },
+        ];
+
+        match apply(&mut conn, &migrations) {
+            Err(MigrationError::Failed { version, .. }) => assert_eq!(version, 2),
+            other => panic!("expected Failed, got {:?}", other),
+        }
+        // The first migration stays applied, nothing of the second one is left behind
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        assert!(table_exists(&conn, "probe"));
+        assert!(!table_exists(&conn, "half_done"));
+    }
+}
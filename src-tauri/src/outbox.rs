@@ -0,0 +1,140 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::config::env_or;
+use crate::database::Database;
+use crate::db_pool::DbPool;
+use crate::error::{AppError, AppResult};
+use crate::mailer::{Mailer, OutgoingEmail};
+
+// Delivery retry policy for queued emails. Defaults can be overridden with environment variables:
+//   OSSMS_MAIL_MAX_ATTEMPTS      attempts before a message is marked failed
+//   OSSMS_MAIL_RETRY_SECS        delay before the first retry
+//   OSSMS_MAIL_RETRY_MAX_SECS    upper bound for the doubling delay
+//   OSSMS_MAIL_POLL_SECS         how often the worker looks for due messages
+pub struct OutboxPolicy {
+    pub max_attempts: i32,
+    pub base_retry_secs: i64,
+    pub max_retry_secs: i64,
+    pub poll_secs: u64,
+}
+
+impl Default for OutboxPolicy {
+    fn default() -> Self {
+        OutboxPolicy {
+            max_attempts: 5,
+            base_retry_secs: 30,
+            max_retry_secs: 60 * 60,
+            poll_secs: 15,
+        }
+    }
+}
+
+impl OutboxPolicy {
+    pub fn from_env() -> Self {
+        let defaults = OutboxPolicy::default();
+        OutboxPolicy {
+            max_attempts: env_or("OSSMS_MAIL_MAX_ATTEMPTS", defaults.max_attempts),
+            base_retry_secs: env_or("OSSMS_MAIL_RETRY_SECS", defaults.base_retry_secs),
+            max_retry_secs: env_or("OSSMS_MAIL_RETRY_MAX_SECS", defaults.max_retry_secs),
+            poll_secs: env_or("OSSMS_MAIL_POLL_SECS", defaults.poll_secs),
+        }
+    }
+
+    // Delay before the next attempt after `attempts` failures, or None once out of attempts.
+    // Each failure doubles the delay, up to the configured maximum.
+    pub fn retry_after(&self, attempts: i32) -> Option<chrono::Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let doublings = (attempts - 1).clamp(0, 30) as u32;
+        let secs = self
+            .base_retry_secs
+            .saturating_mul(1i64 << doublings)
+            .min(self.max_retry_secs);
+        Some(chrono::Duration::seconds(secs))
+    }
+}
+
+// Messages handed to the worker per pass
+const BATCH_SIZE: i64 = 20;
+
+const JOB_NAME: &str = "email_outbox";
+
+pub struct Outbox {
+    pub policy: OutboxPolicy,
+    wake: Notify,
+}
+
+impl Outbox {
+    pub fn new(policy: OutboxPolicy) -> Self {
+        Outbox { policy, wake: Notify::new() }
+    }
+
+    // Queue an email; the worker picks it up straight away. An email carrying a secret
+    // gets the secret's expiry and is dropped if it can't be sent before then.
+    pub fn enqueue(&self, db: &Database, email: &OutgoingEmail, expires_at: Option<&str>) -> AppResult<String> {
+        let id = db.enqueue_email(&email.to, &email.subject, &email.body, email.html_body.as_deref(), expires_at)?;
+        self.wake.notify_one();
+        Ok(id)
+    }
+
+    // Have the worker look at the queue now, e.g. after a retry was requested
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    // Background delivery loop, runs for the lifetime of the app
+    pub async fn run(&self, db: &DbPool, mailer: &RwLock<Arc<dyn Mailer>>) {
+        let mut last_error = None;
+        loop {
+            match self.deliver_due(db, mailer).await {
+                Ok(()) => last_error = None,
+                // A failure that repeats on every pass is only recorded once
+                Err(e) if last_error.as_ref() != Some(&e.message) => {
+                    let message = e.message.clone();
+                    let _ = db.write(move |db| db.record_job_error(JOB_NAME, &message)).await;
+                    last_error = Some(e.message);
+                }
+                Err(_) => {}
+            }
+            let poll = Duration::from_secs(self.policy.poll_secs.max(1));
+            let _ = tokio::time::timeout(poll, self.wake.notified()).await;
+        }
+    }
+
+    async fn deliver_due(&self, db: &DbPool, mailer: &RwLock<Arc<dyn Mailer>>) -> AppResult<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let purge_before = now.clone();
+        db.write(move |db| db.purge_expired_emails(&purge_before)).await?;
+        let due = db.read(move |db| db.get_due_emails(&now, BATCH_SIZE)).await?;
+
+        for queued in due {
+            let email = OutgoingEmail {
+                to: queued.to_address,
+                subject: queued.subject,
+                body: queued.body,
+                html_body: queued.html_body,
+            };
+
+            // No connection is held while sending, a slow server must not block the app
+            let mailer = mailer.read().map_err(|_| AppError::internal("Mailer lock failed"))?.clone();
+            let result = mailer.send(&email).await;
+
+            let attempts = queued.attempts + 1;
+            let next_attempt_at = self
+                .policy
+                .retry_after(attempts)
+                .map(|delay| (chrono::Utc::now() + delay).to_rfc3339());
+            db.write(move |db| match result {
+                Ok(()) => db.mark_email_sent(&queued.id),
+                Err(error) => db.record_email_failure(&queued.id, attempts, &error.to_string(), next_attempt_at.as_deref()),
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+}
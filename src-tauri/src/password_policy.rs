@@ -0,0 +1,73 @@
+use crate::config::env_or;
+use crate::error::{AppError, AppResult};
+
+// Password strength rules applied whenever a password is set. Defaults can be
+// overridden with environment variables:
+//   OSSMS_PASSWORD_MIN_LENGTH         minimum number of characters
+//   OSSMS_PASSWORD_REQUIRE_UPPERCASE  true/false
+//   OSSMS_PASSWORD_REQUIRE_LOWERCASE  true/false
+//   OSSMS_PASSWORD_REQUIRE_DIGIT      true/false
+//   OSSMS_PASSWORD_REQUIRE_SYMBOL     true/false
+//   OSSMS_PASSWORD_HISTORY            previous passwords that may not be reused (0 disables)
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_uppercase: bool,
+    pub require_lowercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub history_depth: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            require_uppercase: true,
+            require_lowercase: true,
+            require_digit: true,
+            require_symbol: false,
+            history_depth: 5,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let defaults = PasswordPolicy::default();
+        PasswordPolicy {
+            min_length: env_or("OSSMS_PASSWORD_MIN_LENGTH", defaults.min_length),
+            require_uppercase: env_or("OSSMS_PASSWORD_REQUIRE_UPPERCASE", defaults.require_uppercase),
+            require_lowercase: env_or("OSSMS_PASSWORD_REQUIRE_LOWERCASE", defaults.require_lowercase),
+            require_digit: env_or("OSSMS_PASSWORD_REQUIRE_DIGIT", defaults.require_digit),
+            require_symbol: env_or("OSSMS_PASSWORD_REQUIRE_SYMBOL", defaults.require_symbol),
+            history_depth: env_or("OSSMS_PASSWORD_HISTORY", defaults.history_depth),
+        }
+    }
+
+    // Check the strength rules, reporting every rule the password breaks at once
+    pub fn validate(&self, password: &str) -> AppResult<()> {
+        let mut problems = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            problems.push(format!("be at least {} characters long", self.min_length));
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            problems.push("contain an uppercase letter".to_string());
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            problems.push("contain a lowercase letter".to_string());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            problems.push("contain a digit".to_string());
+        }
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric() && !c.is_whitespace()) {
+            problems.push("contain a symbol".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::validation(format!("Password must {}", problems.join(", "))))
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use serde::Deserialize;
+use std::fmt;
+
+use crate::database::User;
+use crate::error::{AppError, AppResult};
+
+// Full permission set given to administrator accounts
+pub const ADMIN_PERMISSIONS: &str = r#"{"users": ["view", "create", "edit", "delete"], "supplies": ["view", "create", "edit", "delete"], "supply_histories": ["view", "create", "edit", "delete"], "reports": ["view"]}"#;
+
+// Modules that can be granted in the `users.permissions` JSON column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Module {
+    Users,
+    Supplies,
+    SupplyHistories,
+}
+
+impl Module {
+    const ALL: [Module; 3] = [Module::Users, Module::Supplies, Module::SupplyHistories];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Module::Users => "users",
+            Module::Supplies => "supplies",
+            Module::SupplyHistories => "supply_histories",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    View,
+    Create,
+    Edit,
+    Delete,
+}
+
+impl Action {
+    const ALL: [Action; 4] = [Action::View, Action::Create, Action::Edit, Action::Delete];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::View => "view",
+            Action::Create => "create",
+            Action::Edit => "edit",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Typed view of the permissions JSON, e.g. {"supplies": ["view", "create", "edit"]}
+#[derive(Debug, Default, Deserialize)]
+pub struct Permissions {
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    supplies: Vec<String>,
+    #[serde(default)]
+    supply_histories: Vec<String>,
+}
+
+impl Permissions {
+    // Malformed or missing JSON grants nothing
+    pub fn parse(json: &str) -> Self {
+        serde_json::from_str(json).unwrap_or_default()
+    }
+
+    // Same defaults the frontend applies to staff accounts without explicit permissions
+    fn default_staff() -> Self {
+        Permissions {
+            users: Vec::new(),
+            supplies: vec!["view".into(), "create".into(), "edit".into()],
+            supply_histories: vec!["view".into(), "create".into()],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.users.is_empty()
+            && self.supplies.is_empty()
+            && self.supply_histories.is_empty()
+    }
+
+    fn for_role(role: &str, json: &str) -> Self {
+        let permissions = Permissions::parse(json);
+        if role == "staff" && permissions.is_empty() {
+            Permissions::default_staff()
+        } else {
+            permissions
+        }
+    }
+
+    pub fn allows(&self, module: Module, action: Action) -> bool {
+        let granted = match module {
+            Module::Users => &self.users,
+            Module::Supplies => &self.supplies,
+            Module::SupplyHistories => &self.supply_histories,
+        };
+        granted.iter().any(|a| a == action.as_str())
+    }
+}
+
+// Whether an account with this role and permissions JSON may perform an action
+fn role_allows(role: &str, json: &str, module: Module, action: Action) -> bool {
+    role == "admin" || Permissions::for_role(role, json).allows(module, action)
+}
+
+// Check whether a user may perform an action; admins are allowed everything
+pub fn check_permission(user: &User, module: Module, action: Action) -> AppResult<()> {
+    if role_allows(&user.role, &user.permissions, module, action) {
+        Ok(())
+    } else {
+        Err(AppError::forbidden(format!("Forbidden: missing '{}' permission on {}", action, module.as_str())))
+    }
+}
+
+// Check whether `actor` may give an account this role and permissions. `target` is the
+// account as it is now, or None for a new one. Only administrators can set roles or
+// permissions, never beyond what they hold, and nobody can raise their own privileges.
+pub fn check_grant(actor: &User, target: Option<&User>, role: &str, permissions: &str) -> AppResult<()> {
+    // Leaving them as they are is fine, e.g. a profile edit by someone with users:edit,
+    // as long as the account doesn't outrank them
+    if let Some(target) = target {
+        check_manage(actor, target)?;
+        if target.role == role && same_json(&target.permissions, permissions) {
+            return Ok(());
+        }
+    }
+    
+    if actor.role != "admin" {
+        return Err(AppError::forbidden("Forbidden: administrator access required to set roles or permissions"));
+    }
+    
+    let is_self = target.is_some_and(|t| t.id == actor.id);
+    for module in Module::ALL {
+        for action in Action::ALL {
+            if !role_allows(role, permissions, module, action) {
+                continue;
+            }
+            if check_permission(actor, module, action).is_err() {
+                return Err(AppError::forbidden(format!("Forbidden: cannot grant '{}' on {} without holding it", action, module.as_str())));
+            }
+            if is_self && !role_allows(&actor.role, &actor.permissions, module, action) {
+                return Err(AppError::forbidden("Forbidden: you cannot raise your own privileges"));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Check whether `actor` may act on someone else's account, e.g. reset their password.
+// Administrators can act on anyone; other users only on accounts that hold nothing
+// they don't hold themselves.
+pub fn check_manage(actor: &User, target: &User) -> AppResult<()> {
+    if actor.role == "admin" {
+        return Ok(());
+    }
+    let outranked = target.role == "admin"
+        || Module::ALL.into_iter().any(|module| {
+            Action::ALL.into_iter().any(|action| {
+                role_allows(&target.role, &target.permissions, module, action)
+                    && !role_allows(&actor.role, &actor.permissions, module, action)
+            })
+        });
+    if outranked {
+        return Err(AppError::forbidden("Forbidden: this account has privileges you don't hold"));
+    }
+    Ok(())
+}
+
+// Compare permissions JSON by content, so re-serializing it doesn't count as a change
+fn same_json(a: &str, b: &str) -> bool {
+    match (serde_json::from_str::<serde_json::Value>(a), serde_json::from_str::<serde_json::Value>(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, role: &str, permissions: &str) -> User {
+        User {
+            id: id.to_string(),
+            username: id.to_string(),
+            password: String::new(),
+            firstname: String::new(),
+            lastname: String::new(),
+            email: format!("{}@example.org", id),
+            role: role.to_string(),
+            permissions: permissions.to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn non_admin_cannot_escalate() {
+        let clerk = user("clerk", "staff", r#"{"users": ["view", "edit"], "supplies": ["view"]}"#);
+        let other = user("other", "staff", r#"{"supplies": ["view"]}"#);
+
+        // Making themselves admin, or widening their own or anyone else's permissions
+        assert!(check_grant(&clerk, Some(&clerk), "admin", &clerk.permissions).is_err());
+        assert!(check_grant(&clerk, Some(&clerk), "staff", ADMIN_PERMISSIONS).is_err());
+        assert!(check_grant(&clerk, Some(&other), "staff", r#"{"supplies": ["view", "delete"]}"#).is_err());
+        assert!(check_grant(&clerk, None, "admin", ADMIN_PERMISSIONS).is_err());
+
+        // Profile edits that keep the role and permissions are fine, however the JSON is written
+        assert!(check_grant(&clerk, Some(&other), "staff", r#"{ "supplies":["view"] }"#).is_ok());
+
+        // ...but not on an account that outranks them
+        let admin = user("admin", "admin", ADMIN_PERMISSIONS);
+        assert!(check_grant(&clerk, Some(&admin), "admin", ADMIN_PERMISSIONS).is_err());
+
+        assert!(check_grant(&admin, Some(&other), "admin", ADMIN_PERMISSIONS).is_ok());
+        assert!(check_grant(&admin, None, "staff", r#"{"supplies": ["view"]}"#).is_ok());
+    }
+
+    #[test]
+    fn non_admin_cannot_manage_a_more_privileged_account() {
+        let editor = user("editor", "staff", r#"{"users": ["view", "edit"], "supplies": ["view"]}"#);
+        let admin = user("admin", "admin", ADMIN_PERMISSIONS);
+        let clerk = user("clerk", "staff", r#"{"supplies": ["view"]}"#);
+        let manager = user("manager", "staff", r#"{"supplies": ["view", "delete"]}"#);
+
+        assert_eq!(check_manage(&editor, &admin).unwrap_err().code, crate::error::ErrorCode::Forbidden);
+        assert_eq!(check_manage(&editor, &manager).unwrap_err().code, crate::error::ErrorCode::Forbidden);
+        assert!(check_manage(&editor, &clerk).is_ok());
+        assert!(check_manage(&admin, &editor).is_ok());
+    }
+}
@@ -0,0 +1,54 @@
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::config::env_or;
+
+// Limits on password reset requests. Defaults can be overridden with environment variables:
+//   OSSMS_RESET_MAX_PER_EMAIL  requests per email address within the window
+//   OSSMS_RESET_MAX_GLOBAL     requests across all addresses within the window
+//   OSSMS_RESET_WINDOW_SECS    length of the sliding window
+pub struct ResetThrottlePolicy {
+    pub max_per_email: i64,
+    pub max_global: i64,
+    pub window_secs: i64,
+}
+
+impl Default for ResetThrottlePolicy {
+    fn default() -> Self {
+        ResetThrottlePolicy {
+            max_per_email: 3,
+            max_global: 30,
+            window_secs: 60 * 60,
+        }
+    }
+}
+
+impl ResetThrottlePolicy {
+    pub fn from_env() -> Self {
+        let defaults = ResetThrottlePolicy::default();
+        ResetThrottlePolicy {
+            max_per_email: env_or("OSSMS_RESET_MAX_PER_EMAIL", defaults.max_per_email),
+            max_global: env_or("OSSMS_RESET_MAX_GLOBAL", defaults.max_global),
+            window_secs: env_or("OSSMS_RESET_WINDOW_SECS", defaults.window_secs),
+        }
+    }
+}
+
+// Random 32-character token sent to the user; only its hash is stored
+pub fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32)
+        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+        .collect()
+}
+
+// Tokens are random, so a plain SHA-256 is enough to keep them unusable if the database leaks
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.trim().as_bytes()))
+}
+
+// Compare a submitted token against a stored hash without leaking timing
+pub fn token_matches(token: &str, stored_hash: &str) -> bool {
+    bool::from(hash_token(token).as_bytes().ct_eq(stored_hash.as_bytes()))
+}
@@ -0,0 +1,330 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde::Serialize;
+
+use crate::config::env_or;
+use crate::database::SmtpSettings;
+use crate::error::{AppError, AppResult};
+use crate::mailer::{build_message, sender, MailError, MailTransport, Mailer, OutgoingEmail, SendFuture};
+
+// Shown instead of the stored SMTP password
+pub const PASSWORD_MASK: &str = "********";
+
+const SEND_TIMEOUT_SECS: u64 = 30;
+
+// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    // Plain connection, only meant for local test servers
+    None,
+    // Upgrade a plain connection with STARTTLS (usually port 587)
+    StartTls,
+    // TLS from the first byte (usually port 465)
+    Tls,
+}
+
+impl TlsMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "none" => Ok(TlsMode::None),
+            "starttls" => Ok(TlsMode::StartTls),
+            "tls" => Ok(TlsMode::Tls),
+            other => Err(format!("Unknown TLS mode '{}', expected none, starttls or tls", other)),
+        }
+    }
+}
+
+impl Default for SmtpSettings {
+    fn default() -> Self {
+        SmtpSettings {
+            transport: "smtp".to_string(),
+            host: String::new(),
+            port: 587,
+            tls_mode: "starttls".to_string(),
+            username: String::new(),
+            password: String::new(),
+            from_address: "noreply@ossms.com".to_string(),
+            drop_dir: String::new(),
+            organization_name: "OSSMS".to_string(),
+            logo_url: String::new(),
+        }
+    }
+}
+
+// Environment variables that take precedence over the stored settings
+const ENV_TRANSPORT: &str = "OSSMS_MAIL_TRANSPORT";
+const ENV_DROP_DIR: &str = "OSSMS_MAIL_DROP_DIR";
+const ENV_HOST: &str = "OSSMS_SMTP_HOST";
+const ENV_PORT: &str = "OSSMS_SMTP_PORT";
+const ENV_TLS: &str = "OSSMS_SMTP_TLS";
+const ENV_USERNAME: &str = "OSSMS_SMTP_USERNAME";
+const ENV_PASSWORD: &str = "OSSMS_SMTP_PASSWORD";
+const ENV_FROM: &str = "OSSMS_SMTP_FROM";
+const ENV_ORG_NAME: &str = "OSSMS_ORG_NAME";
+const ENV_LOGO_URL: &str = "OSSMS_ORG_LOGO_URL";
+
+// Settings actually used for sending: the stored ones (or defaults) with any
+// OSSMS_MAIL_*, OSSMS_SMTP_* and OSSMS_ORG_* environment variables applied on top
+pub fn effective_settings(stored: Option<SmtpSettings>) -> SmtpSettings {
+    let stored = stored.unwrap_or_default();
+    SmtpSettings {
+        transport: env_or(ENV_TRANSPORT, stored.transport),
+        host: env_or(ENV_HOST, stored.host),
+        port: env_or(ENV_PORT, stored.port),
+        tls_mode: env_or(ENV_TLS, stored.tls_mode),
+        username: env_or(ENV_USERNAME, stored.username),
+        // Taken as is: spaces at either end can be part of a password
+        password: std::env::var(ENV_PASSWORD).unwrap_or(stored.password),
+        from_address: env_or(ENV_FROM, stored.from_address),
+        drop_dir: env_or(ENV_DROP_DIR, stored.drop_dir),
+        organization_name: env_or(ENV_ORG_NAME, stored.organization_name),
+        logo_url: env_or(ENV_LOGO_URL, stored.logo_url),
+    }
+}
+
+fn env_overrides() -> Vec<String> {
+    [
+        ("transport", ENV_TRANSPORT),
+        ("host", ENV_HOST),
+        ("port", ENV_PORT),
+        ("tls_mode", ENV_TLS),
+        ("username", ENV_USERNAME),
+        ("password", ENV_PASSWORD),
+        ("from_address", ENV_FROM),
+        ("drop_dir", ENV_DROP_DIR),
+        ("organization_name", ENV_ORG_NAME),
+        ("logo_url", ENV_LOGO_URL),
+    ]
+    .into_iter()
+    .filter(|(_, key)| std::env::var(key).is_ok())
+    .map(|(field, _)| field.to_string())
+    .collect()
+}
+
+// What admins get to see: the password is never sent back, only whether one is set
+#[derive(Serialize)]
+pub struct MaskedSmtpSettings {
+    pub transport: String,
+    pub host: String,
+    pub port: u16,
+    pub tls_mode: String,
+    pub username: String,
+    pub password: String,
+    pub from_address: String,
+    pub drop_dir: String,
+    pub organization_name: String,
+    pub logo_url: String,
+    // Fields currently overridden by environment variables
+    pub overridden_by_env: Vec<String>,
+}
+
+pub fn masked(settings: &SmtpSettings) -> MaskedSmtpSettings {
+    MaskedSmtpSettings {
+        transport: settings.transport.clone(),
+        host: settings.host.clone(),
+        port: settings.port,
+        tls_mode: settings.tls_mode.clone(),
+        username: settings.username.clone(),
+        password: if settings.password.is_empty() { String::new() } else { PASSWORD_MASK.to_string() },
+        from_address: settings.from_address.clone(),
+        drop_dir: settings.drop_dir.clone(),
+        organization_name: settings.organization_name.clone(),
+        logo_url: settings.logo_url.clone(),
+        overridden_by_env: env_overrides(),
+    }
+}
+
+pub fn validate(settings: &SmtpSettings) -> AppResult<()> {
+    if MailTransport::parse(&settings.transport).map_err(AppError::validation)? == MailTransport::Smtp {
+        if settings.host.trim().is_empty() {
+            return Err(AppError::validation("SMTP host is required"));
+        }
+        if settings.port == 0 {
+            return Err(AppError::validation("SMTP port must be between 1 and 65535"));
+        }
+    }
+    TlsMode::parse(&settings.tls_mode).map_err(AppError::validation)?;
+    settings.from_address.parse::<Mailbox>()
+        .map_err(|e| AppError::validation(format!("Invalid from address: {}", e)))?;
+    Ok(())
+}
+
+type SmtpTransport = AsyncSmtpTransport<Tokio1Executor>;
+
+fn build_transport(settings: &SmtpSettings) -> Result<SmtpTransport, MailError> {
+    let host = settings.host.trim();
+    let builder = match TlsMode::parse(&settings.tls_mode).map_err(MailError::Config)? {
+        TlsMode::None => SmtpTransport::builder_dangerous(host),
+        TlsMode::StartTls => SmtpTransport::starttls_relay(host)
+            .map_err(|e| MailError::Config(format!("invalid SMTP host: {}", e)))?,
+        TlsMode::Tls => SmtpTransport::relay(host)
+            .map_err(|e| MailError::Config(format!("invalid SMTP host: {}", e)))?,
+    };
+
+    let mut builder = builder
+        .port(settings.port)
+        .timeout(Some(std::time::Duration::from_secs(SEND_TIMEOUT_SECS)));
+    if !settings.username.is_empty() {
+        builder = builder.credentials(Credentials::new(settings.username.clone(), settings.password.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+// Sends through the configured SMTP server. The connection is set up per email,
+// so a misconfigured server only fails the sends, not startup.
+pub struct SmtpMailer {
+    settings: SmtpSettings,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(settings: SmtpSettings) -> Self {
+        let from = sender(&settings);
+        SmtpMailer { settings, from }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send<'a>(&'a self, email: &'a OutgoingEmail) -> SendFuture<'a> {
+        Box::pin(async move {
+            if self.settings.host.trim().is_empty() {
+                return Err(MailError::NotConfigured);
+            }
+
+            let message = build_message(&self.from, email)?;
+            build_transport(&self.settings)?
+                .send(message)
+                .await
+                .map_err(|e| MailError::Delivery(e.to_string()))?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    fn smtp(host: &str) -> SmtpSettings {
+        SmtpSettings { host: host.to_string(), ..SmtpSettings::default() }
+    }
+
+    // Stand-in SMTP server for a single connection. Accepts everything and hands over
+    // the data of each message it receives.
+    fn smtp_stand_in() -> (u16, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_uppercase();
+                if command == "DATA" {
+                    writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").unwrap();
+                    let mut data = String::new();
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if line == ".\r\n" {
+                            break;
+                        }
+                        data.push_str(&line);
+                    }
+                    writer.write_all(b"250 Queued\r\n").unwrap();
+                    let _ = received.send(data);
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                }
+                line.clear();
+            }
+        });
+
+        (port, receiver)
+    }
+
+    #[test]
+    fn sends_through_a_plain_smtp_server() {
+        let (port, received) = smtp_stand_in();
+        let mailer = SmtpMailer::new(SmtpSettings { port, tls_mode: "none".to_string(), ..smtp("127.0.0.1") });
+        let email = OutgoingEmail {
+            to: "ann@example.org".to_string(),
+            subject: "Low stock".to_string(),
+            body: "Paper is running low".to_string(),
+            html_body: None,
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(mailer.send(&email)).unwrap();
+
+        let data = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(data.contains("To: ann@example.org"), "{}", data);
+        assert!(data.contains("Subject: Low stock"), "{}", data);
+        assert!(data.contains("<noreply@ossms.com>"), "{}", data);
+        assert!(data.contains("Paper is running low"), "{}", data);
+
+        let unknown_tls = SmtpSettings { tls_mode: "ssl".to_string(), ..smtp("127.0.0.1") };
+        assert!(matches!(build_transport(&unknown_tls), Err(MailError::Config(_))));
+    }
+
+    #[test]
+    fn validate_rejects_incomplete_settings() {
+        assert!(validate(&smtp("mail.example.org")).is_ok());
+
+        assert!(validate(&smtp(" ")).is_err());
+        assert!(validate(&SmtpSettings { port: 0, ..smtp("mail.example.org") }).is_err());
+        assert!(validate(&SmtpSettings { tls_mode: "ssl".to_string(), ..smtp("mail.example.org") }).is_err());
+        assert!(validate(&SmtpSettings { from_address: "not an address".to_string(), ..smtp("mail.example.org") }).is_err());
+        assert!(validate(&SmtpSettings { transport: "pigeon".to_string(), ..smtp("mail.example.org") }).is_err());
+
+        // Only SMTP needs a server
+        assert!(validate(&SmtpSettings { transport: "file".to_string(), ..smtp("") }).is_ok());
+    }
+
+    // The only test touching OSSMS_* variables, the environment is shared between tests
+    #[test]
+    fn environment_overrides_stored_settings() {
+        let stored = SmtpSettings {
+            username: "clerk".to_string(),
+            password: "secret".to_string(),
+            ..smtp("stored.example.org")
+        };
+        std::env::set_var(ENV_HOST, "env.example.org");
+        std::env::set_var(ENV_PORT, "2525");
+        // Values are trimmed
+        std::env::set_var(ENV_TLS, " tls ");
+        // ...except the password
+        std::env::set_var(ENV_PASSWORD, " pass word ");
+        let settings = effective_settings(Some(stored));
+        let shown = masked(&settings);
+        for key in [ENV_HOST, ENV_PORT, ENV_TLS, ENV_PASSWORD] {
+            std::env::remove_var(key);
+        }
+
+        assert_eq!(settings.host, "env.example.org");
+        assert_eq!(settings.port, 2525);
+        assert_eq!(settings.tls_mode, "tls");
+        assert_eq!(settings.username, "clerk");
+        assert_eq!(settings.password, " pass word ");
+        assert_eq!(shown.password, PASSWORD_MASK);
+        assert_eq!(shown.overridden_by_env, ["host", "port", "tls_mode", "password"]);
+
+        assert_eq!(effective_settings(None).host, "");
+        assert_eq!(masked(&smtp("")).password, "");
+    }
+}
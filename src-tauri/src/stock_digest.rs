@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::env_or;
+use crate::database::{Database, Supply, STOCK_STATUS_LOW, STOCK_STATUS_MODERATE};
+use crate::db_pool::DbPool;
+use crate::email_templates::{self, Branding, EmailTemplate};
+use crate::error::AppResult;
+use crate::outbox::Outbox;
+use crate::permissions::{check_permission, Action, Module};
+use crate::smtp;
+
+// Low-stock digest schedule. Defaults can be overridden with environment variables:
+//   OSSMS_STOCK_DIGEST_HOURS  hours between digests (0 turns the digest off)
+pub struct StockDigestPolicy {
+    pub interval_hours: i64,
+}
+
+impl Default for StockDigestPolicy {
+    fn default() -> Self {
+        StockDigestPolicy { interval_hours: 24 }
+    }
+}
+
+impl StockDigestPolicy {
+    pub fn from_env() -> Self {
+        let defaults = StockDigestPolicy::default();
+        StockDigestPolicy {
+            interval_hours: env_or("OSSMS_STOCK_DIGEST_HOURS", defaults.interval_hours),
+        }
+    }
+}
+
+const JOB_NAME: &str = "stock_digest";
+
+// The app may not run for a whole interval at a time, so the last run is kept in the
+// database and checked every few minutes
+const CHECK_INTERVAL_SECS: u64 = 5 * 60;
+
+// Background schedule, runs for the lifetime of the app
+pub async fn run(policy: &StockDigestPolicy, db: &DbPool, outbox: &Arc<Outbox>) {
+    if policy.interval_hours <= 0 {
+        return;
+    }
+
+    let mut last_error = None;
+    loop {
+        match run_if_due(policy, db, outbox).await {
+            Ok(()) => last_error = None,
+            // A failure that repeats on every check is only recorded once
+            Err(e) if last_error.as_ref() != Some(&e.message) => {
+                let message = e.message.clone();
+                let _ = db.write(move |db| db.record_job_error(JOB_NAME, &message)).await;
+                last_error = Some(e.message);
+            }
+            Err(_) => {}
+        }
+        tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+    }
+}
+
+async fn run_if_due(policy: &StockDigestPolicy, db: &DbPool, outbox: &Arc<Outbox>) -> AppResult<()> {
+    let interval = chrono::Duration::hours(policy.interval_hours);
+    let outbox = Arc::clone(outbox);
+    db.write(move |db| {
+        let now = chrono::Utc::now();
+        let last_run = db.get_job_last_run(JOB_NAME)?;
+        let due = match last_run.and_then(|last| chrono::DateTime::parse_from_rfc3339(&last).ok()) {
+            Some(last) => now.signed_duration_since(last) >= interval,
+            None => true,
+        };
+        if !due {
+            return Ok(());
+        }
+
+        run_now(db, &outbox).map(|_| ())
+    })
+    .await
+}
+
+// Send the digests and restart the schedule from now, so a manual run isn't followed by
+// a scheduled one reporting the same thing. Returns the number of emails queued.
+pub fn run_now(db: &Database, outbox: &Outbox) -> AppResult<usize> {
+    let queued = send_digests(db, outbox)?;
+    db.set_job_last_run(JOB_NAME, &chrono::Utc::now().to_rfc3339())?;
+    Ok(queued)
+}
+
+// Queue a digest for every subscriber with something new to report. Returns the number of emails queued.
+fn send_digests(db: &Database, outbox: &Outbox) -> AppResult<usize> {
+    // Forget reports for items whose status has changed since, so they are alerted again
+    db.clear_changed_stock_reports()?;
+
+    let settings = smtp::effective_settings(db.get_smtp_settings()?);
+    let branding = Branding::from_settings(&settings);
+
+    let mut queued = 0;
+    for subscription in db.get_stock_digest_subscriptions()? {
+        // Only users who may still see supplies get the digest
+        let user = match db.get_user_by_id(&subscription.user_id)? {
+            Some(user) => user,
+            None => continue,
+        };
+        if check_permission(&user, Module::Supplies, Action::View).is_err() {
+            continue;
+        }
+
+        let items = db.get_unreported_stock_items(&user.id, subscription.include_moderate)?;
+        if items.is_empty() {
+            continue;
+        }
+
+        let email = digest_email(&branding, &user.email, &items);
+        outbox.enqueue(db, &email, None)?;
+        db.record_stock_reports(&user.id, &items)?;
+        queued += 1;
+    }
+
+    Ok(queued)
+}
+
+fn digest_email(branding: &Branding, to: &str, items: &[Supply]) -> crate::mailer::OutgoingEmail {
+    let low = items.iter().filter(|s| s.status == STOCK_STATUS_LOW).count();
+    let moderate = items.iter().filter(|s| s.status == STOCK_STATUS_MODERATE).count();
+
+    let title = match (low, moderate) {
+        (_, 0) => format!("{} item{} low on stock", low, plural(low)),
+        (0, _) => format!("{} item{} running low", moderate, plural(moderate)),
+        _ => format!("{} item{} low on stock, {} running low", low, plural(low), moderate),
+    };
+
+    let lines: Vec<String> = items
+        .iter()
+        .map(|supply| {
+            let mut name = supply.name.clone();
+            let details: Vec<&str> = [supply.variation.as_deref(), supply.brand.as_deref()]
+                .into_iter()
+                .flatten()
+                .filter(|detail| !detail.trim().is_empty())
+                .collect();
+            if !details.is_empty() {
+                name = format!("{} ({})", name, details.join(", "));
+            }
+            format!(
+                "- {}: {} {} left, minimum {} [{}]",
+                name, supply.quantity, supply.unit, supply.min_quantity, supply.status
+            )
+        })
+        .collect();
+    let message = format!("{}\n\nCheck the low-stock report for details.", lines.join("\n"));
+
+    email_templates::render(EmailTemplate::Alert, branding, to, &[("title", &title), ("message", &message)])
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}